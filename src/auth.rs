@@ -0,0 +1,507 @@
+use crate::error::ApiError;
+use crate::rate_limit::{self, RateLimiter};
+use crate::secure_account::BankAccount;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::middleware::Next;
+use actix_web::{FromRequest, HttpMessage, HttpRequest, web};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::future::{Ready, ready};
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+const API_KEY: &str = "x-api-key";
+
+/// Who sent a request, as established by its credentials.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub subject: String,
+    /// May use the admin and ledger endpoints.
+    pub admin: bool,
+}
+
+impl Principal {
+    /// Whether this principal may see and use `account`: only its owner may,
+    /// apart from admins. Accounts without an owner are for admins only.
+    pub fn may_access(&self, account: &BankAccount) -> bool {
+        self.admin || account.owner() == Some(self.subject.as_str())
+    }
+}
+
+/// Lets handlers take the principal as an argument. Fails with 401 on routes
+/// that were left open and reached without credentials.
+impl FromRequest for Principal {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Principal>()
+                .cloned()
+                .ok_or(ApiError::Unauthenticated),
+        )
+    }
+}
+
+/// The claims read from a JWT. `exp` and `sub` are required; `admin` defaults
+/// to false.
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+struct ApiKey {
+    key: String,
+    principal: Principal,
+}
+
+/// Checks the credentials of incoming requests: static API keys sent in
+/// `X-API-Key`, and JWTs sent as `Authorization: Bearer`, signed with HS256 or
+/// EdDSA by keys we hold ourselves. Nothing is accepted until configured.
+#[derive(Default)]
+pub struct Authenticator {
+    api_keys: Vec<ApiKey>,
+    hs256: Option<DecodingKey>,
+    eddsa: Option<DecodingKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl Authenticator {
+    /// Loads API keys from a text file with one `SUBJECT KEY [admin]` per line.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn load_api_keys(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {reason}", path.display(), index + 1),
+                )
+            };
+            let (subject, key, admin) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [subject, key] => (subject, key, false),
+                [subject, key, "admin"] => (subject, key, true),
+                _ => return Err(invalid("expected SUBJECT KEY [admin]")),
+            };
+            if self.api_keys.iter().any(|known| known.key == key) {
+                return Err(invalid("duplicate key"));
+            }
+            self.api_keys.push(ApiKey {
+                key: key.to_owned(),
+                principal: Principal {
+                    subject: subject.to_owned(),
+                    admin,
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// Accepts JWTs signed with HS256 and this shared secret.
+    pub fn accept_hs256(&mut self, secret: &[u8]) {
+        self.hs256 = Some(DecodingKey::from_secret(secret));
+    }
+
+    /// Accepts JWTs signed with EdDSA by the private half of this Ed25519 public
+    /// key, given in PEM.
+    pub fn accept_eddsa(&mut self, public_key_pem: &[u8]) -> io::Result<()> {
+        let key = DecodingKey::from_ed_pem(public_key_pem)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.eddsa = Some(key);
+        Ok(())
+    }
+
+    /// Only accepts JWTs whose `iss` claim is `issuer`.
+    pub fn require_issuer(&mut self, issuer: String) {
+        self.issuer = Some(issuer);
+    }
+
+    /// Only accepts JWTs whose `aud` claim includes `audience`.
+    pub fn require_audience(&mut self, audience: String) {
+        self.audience = Some(audience);
+    }
+
+    pub fn api_key_count(&self) -> usize {
+        self.api_keys.len()
+    }
+
+    pub fn accepts_jwts(&self) -> bool {
+        self.hs256.is_some() || self.eddsa.is_some()
+    }
+
+    /// Whether `headers` carry credentials at all, right or wrong.
+    fn presents_credentials(headers: &HeaderMap) -> bool {
+        headers.contains_key(API_KEY) || headers.contains_key(header::AUTHORIZATION)
+    }
+
+    /// The principal named by the credentials in `headers`, or `None` if there
+    /// are none. Credentials that are present but wrong are an error.
+    fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Principal>, ApiError> {
+        if let Some(value) = headers.get(API_KEY) {
+            let key = value.to_str().map_err(|_| ApiError::InvalidCredentials)?;
+            return self.api_key(key).map(Some);
+        }
+        if let Some(value) = headers.get(header::AUTHORIZATION) {
+            let token = value
+                .to_str()
+                .ok()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or(ApiError::InvalidCredentials)?;
+            return self.jwt(token).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn api_key(&self, key: &str) -> Result<Principal, ApiError> {
+        // Compare against every key, so the time taken says nothing about which
+        // keys exist or how much of one was guessed right.
+        let mut found = None;
+        for known in &self.api_keys {
+            if constant_time_eq(known.key.as_bytes(), key.as_bytes()) {
+                found = Some(&known.principal);
+            }
+        }
+        found.cloned().ok_or(ApiError::InvalidCredentials)
+    }
+
+    fn jwt(&self, token: &str) -> Result<Principal, ApiError> {
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|_| ApiError::InvalidCredentials)?;
+        // The token names its algorithm, but only the ones we hold a key for count.
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256.as_ref(),
+            Algorithm::EdDSA => self.eddsa.as_ref(),
+            _ => None,
+        }
+        .ok_or(ApiError::InvalidCredentials)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims = jsonwebtoken::decode::<Claims>(token, key, &validation)
+            .map_err(|_| ApiError::InvalidCredentials)?
+            .claims;
+        Ok(Principal {
+            subject: claims.sub,
+            admin: claims.admin,
+        })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Middleware for the whole app: attaches the principal named by a request's
+/// credentials, if it has any. Wrong credentials are refused even on open routes,
+/// and count against the peer address's allowance for wrong credentials; once
+/// that is used up, its credentials are refused with 429 without being checked.
+pub async fn authenticate<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let authenticator = req
+        .app_data::<web::Data<Authenticator>>()
+        .expect("the authenticator is registered as app data");
+    let limiter = req
+        .app_data::<web::Data<RateLimiter>>()
+        .expect("the rate limiter is registered as app data");
+    if Authenticator::presents_credentials(req.headers()) {
+        let address = rate_limit::peer_address(&req);
+        if let Err(retry_after) = limiter.check_failed_auth(address, Instant::now()) {
+            return Ok(ApiError::RateLimited { retry_after }.refuse(req));
+        }
+        match authenticator.authenticate(req.headers()) {
+            Ok(Some(principal)) => {
+                req.extensions_mut().insert(principal);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                limiter.record_failed_auth(address, Instant::now());
+                return Ok(e.refuse(req));
+            }
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Middleware for scopes that need a principal.
+pub async fn require_principal<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    if !req.extensions().contains::<Principal>() {
+        return Ok(ApiError::Unauthenticated.refuse(req));
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Middleware for scopes that need an admin principal.
+pub async fn require_admin<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let admin = req.extensions().get::<Principal>().map(|p| p.admin);
+    match admin {
+        None => return Ok(ApiError::Unauthenticated.refuse(req)),
+        Some(false) => return Ok(ApiError::Forbidden.refuse(req)),
+        Some(true) => {}
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::http::header::HeaderValue;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use actix_web::{App, HttpResponse};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{Value, json};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const SECRET: &[u8] = b"test-secret";
+
+    fn authenticator() -> Authenticator {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "# keys\nalice alice-key\n\nops ops-key admin").unwrap();
+        let mut authenticator = Authenticator::default();
+        authenticator.load_api_keys(file.path()).unwrap();
+        authenticator.accept_hs256(SECRET);
+        authenticator
+    }
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::HeaderName::from_static(name),
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    fn token(claims: Value, secret: &[u8]) -> String {
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    /// The Unix time `hours` hours from now, in the past if `hours` is negative.
+    fn in_hours(hours: i64) -> i64 {
+        chrono::Utc::now().timestamp() + hours * 3600
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        headers("authorization", &format!("Bearer {token}"))
+    }
+
+    fn subject(result: Result<Option<Principal>, ApiError>) -> Option<(String, bool)> {
+        result
+            .unwrap()
+            .map(|principal| (principal.subject, principal.admin))
+    }
+
+    fn refused(result: Result<Option<Principal>, ApiError>) -> &'static str {
+        result.unwrap_err().code()
+    }
+
+    #[test]
+    fn an_api_key_names_its_principal() {
+        let auth = authenticator();
+        assert_eq!(auth.api_key_count(), 2);
+        assert_eq!(
+            subject(auth.authenticate(&headers(API_KEY, "alice-key"))),
+            Some(("alice".to_owned(), false))
+        );
+        assert_eq!(
+            subject(auth.authenticate(&headers(API_KEY, "ops-key"))),
+            Some(("ops".to_owned(), true))
+        );
+        assert_eq!(subject(auth.authenticate(&HeaderMap::new())), None);
+    }
+
+    #[test]
+    fn only_an_exact_api_key_matches() {
+        let auth = authenticator();
+        for key in ["alice-ke", "alice-key2", "ALICE-KEY", "", "bob-key"] {
+            assert_eq!(
+                refused(auth.authenticate(&headers(API_KEY, key))),
+                "invalid_credentials",
+                "{key:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_key_file_with_a_bad_or_duplicate_line_is_refused() {
+        for contents in ["alice\n", "alice key admin extra\n", "a k\nb k\n"] {
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(contents.as_bytes()).unwrap();
+            let error = Authenticator::default()
+                .load_api_keys(file.path())
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{contents:?}");
+        }
+    }
+
+    #[test]
+    fn a_signed_token_names_its_principal() {
+        let auth = authenticator();
+        let alice = token(json!({ "sub": "alice", "exp": in_hours(1) }), SECRET);
+        assert_eq!(
+            subject(auth.authenticate(&bearer(&alice))),
+            Some(("alice".to_owned(), false))
+        );
+        let ops = token(
+            json!({ "sub": "ops", "exp": in_hours(1), "admin": true }),
+            SECRET,
+        );
+        assert_eq!(
+            subject(auth.authenticate(&bearer(&ops))),
+            Some(("ops".to_owned(), true))
+        );
+    }
+
+    #[test]
+    fn expired_forged_or_incomplete_tokens_are_refused() {
+        let auth = authenticator();
+        for token in [
+            token(json!({ "sub": "alice", "exp": in_hours(-1) }), SECRET),
+            token(
+                json!({ "sub": "alice", "exp": in_hours(1) }),
+                b"other-secret",
+            ),
+            token(json!({ "sub": "alice" }), SECRET),
+            token(json!({ "exp": in_hours(1) }), SECRET),
+            "not.a.token".to_owned(),
+        ] {
+            assert_eq!(
+                refused(auth.authenticate(&bearer(&token))),
+                "invalid_credentials",
+                "{token}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_token_needs_the_bearer_scheme() {
+        let auth = authenticator();
+        let token = token(json!({ "sub": "alice", "exp": in_hours(1) }), SECRET);
+        for value in [
+            token.clone(),
+            format!("Basic {token}"),
+            format!("bearer{token}"),
+        ] {
+            assert_eq!(
+                refused(auth.authenticate(&headers("authorization", &value))),
+                "invalid_credentials"
+            );
+        }
+    }
+
+    #[test]
+    fn tokens_are_refused_unless_a_key_for_their_algorithm_is_configured() {
+        let mut auth = Authenticator::default();
+        auth.require_issuer("bank".to_owned());
+        let token = token(
+            json!({ "sub": "alice", "exp": in_hours(1), "iss": "bank" }),
+            SECRET,
+        );
+        assert!(!auth.accepts_jwts());
+        assert_eq!(
+            refused(auth.authenticate(&bearer(&token))),
+            "invalid_credentials"
+        );
+        auth.accept_hs256(SECRET);
+        assert!(subject(auth.authenticate(&bearer(&token))).is_some());
+    }
+
+    #[test]
+    fn a_configured_issuer_and_audience_must_match() {
+        let mut auth = authenticator();
+        auth.require_issuer("bank".to_owned());
+        auth.require_audience("api".to_owned());
+        let claims = |iss: &str, aud: &str| {
+            token(
+                json!({ "sub": "alice", "exp": in_hours(1), "iss": iss, "aud": aud }),
+                SECRET,
+            )
+        };
+        assert!(subject(auth.authenticate(&bearer(&claims("bank", "api")))).is_some());
+        for token in [claims("elsewhere", "api"), claims("bank", "other")] {
+            assert_eq!(
+                refused(auth.authenticate(&bearer(&token))),
+                "invalid_credentials"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn scopes_admit_principals_and_admins_only() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(authenticator()))
+                .app_data(web::Data::new(RateLimiter::default()))
+                .service(
+                    web::scope("/admin")
+                        .wrap(from_fn(require_admin))
+                        .route("", web::get().to(HttpResponse::Ok)),
+                )
+                .service(
+                    web::scope("/accounts")
+                        .wrap(from_fn(require_principal))
+                        .route("", web::get().to(HttpResponse::Ok)),
+                )
+                .route("/open", web::get().to(HttpResponse::Ok))
+                .wrap(from_fn(authenticate)),
+        )
+        .await;
+
+        for (path, key, status) in [
+            ("/open", None, StatusCode::OK),
+            ("/open", Some("wrong-key"), StatusCode::UNAUTHORIZED),
+            ("/accounts", None, StatusCode::UNAUTHORIZED),
+            ("/accounts", Some("alice-key"), StatusCode::OK),
+            ("/admin", None, StatusCode::UNAUTHORIZED),
+            ("/admin", Some("alice-key"), StatusCode::FORBIDDEN),
+            ("/admin", Some("ops-key"), StatusCode::OK),
+        ] {
+            let mut req = TestRequest::get().uri(path);
+            if let Some(key) = key {
+                req = req.insert_header((API_KEY, key));
+            }
+            let response = call_service(&app, req.to_request()).await;
+            assert_eq!(response.status(), status, "{path} with {key:?}");
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::error::DomainError;
+use crate::money::Money;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A three-letter ISO 4217 style currency code such as `USD` or `EUR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    /// The currency used when a request does not name one.
+    pub const DEFAULT: Currency = Currency(*b"USD");
+
+    pub fn as_str(&self) -> &str {
+        // The constructor only accepts ASCII uppercase letters.
+        std::str::from_utf8(&self.0).expect("currency codes are ASCII")
+    }
+}
+
+impl FromStr for Currency {
+    type Err = DomainError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            &[a, b, c] if [a, b, c].iter().all(u8::is_ascii_uppercase) => Ok(Currency([a, b, c])),
+            _ => Err(DomainError::InvalidCurrency),
+        }
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = DomainError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.as_str().to_owned()
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Number of decimal places kept for exchange rates.
+const RATE_SCALE: i64 = 1_000_000;
+
+/// A fixed-point exchange rate: how many minor units of the target currency one
+/// minor unit of the source currency buys. Kept as an integer so conversions never
+/// touch floating point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExchangeRate {
+    micros: i64,
+}
+
+impl ExchangeRate {
+    pub const IDENTITY: ExchangeRate = ExchangeRate { micros: RATE_SCALE };
+
+    /// Converts `amount` at this rate, rounding toward zero.
+    pub fn convert(self, amount: Money) -> Result<Money, DomainError> {
+        let converted =
+            i128::from(amount.minor_units()) * i128::from(self.micros) / i128::from(RATE_SCALE);
+        i64::try_from(converted)
+            .map(Money::from_minor_units)
+            .map_err(|_| DomainError::AmountOverflow)
+    }
+}
+
+impl FromStr for ExchangeRate {
+    type Err = &'static str;
+
+    /// Parses a positive decimal such as `1.0845` with at most six fractional digits.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const INVALID: &str =
+            "Exchange rate must be a positive decimal with at most six decimal places.";
+
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        if whole.is_empty()
+            || fraction.len() > 6
+            || !whole
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(INVALID);
+        }
+        let whole: i64 = whole.parse().map_err(|_| INVALID)?;
+        let fraction: i64 = format!("{fraction:0<6}").parse().map_err(|_| INVALID)?;
+        let micros = whole
+            .checked_mul(RATE_SCALE)
+            .and_then(|w| w.checked_add(fraction))
+            .filter(|&m| m > 0)
+            .ok_or(INVALID)?;
+        Ok(ExchangeRate { micros })
+    }
+}
+
+impl fmt::Display for ExchangeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:06}",
+            self.micros / RATE_SCALE,
+            self.micros % RATE_SCALE
+        )
+    }
+}
+
+/// Rates are rendered as decimal strings so clients never lose precision.
+impl Serialize for ExchangeRate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Exchange rates loaded from a local file at startup.
+///
+/// The file holds one rate per line in the form `FROM TO RATE`, e.g. `EUR USD 1.0845`.
+/// Blank lines and lines starting with `#` are ignored. Only the listed direction is
+/// available; the inverse is never derived, to avoid silent rounding differences.
+#[derive(Debug, Default)]
+pub struct RateTable {
+    rates: HashMap<(Currency, Currency), ExchangeRate>,
+}
+
+impl RateTable {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut rates = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &dyn fmt::Display| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {}: {reason}", index + 1),
+                )
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [from, to, rate] = fields[..] else {
+                return Err(invalid(&"expected `FROM TO RATE`"));
+            };
+            let from: Currency = from.parse().map_err(|e| invalid(&e))?;
+            let to: Currency = to.parse().map_err(|e| invalid(&e))?;
+            let rate: ExchangeRate = rate.parse().map_err(|e| invalid(&e))?;
+            rates.insert((from, to), rate);
+        }
+
+        Ok(Self { rates })
+    }
+
+    /// Looks up the rate for converting `from` into `to`.
+    /// Converting a currency into itself always uses the identity rate.
+    pub fn rate(&self, from: Currency, to: Currency) -> Option<ExchangeRate> {
+        if from == to {
+            return Some(ExchangeRate::IDENTITY);
+        }
+        self.rates.get(&(from, to)).copied()
+    }
+}
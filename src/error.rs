@@ -0,0 +1,396 @@
+use crate::schedule::ScheduleError;
+use crate::store::StoreError;
+use crate::validation::FieldError;
+use actix_web::body::EitherBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+/// Why a business rule refused an operation.
+///
+/// Every variant has a stable [`code`](DomainError::code) that clients can match
+/// on; the message is meant for people and may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DomainError {
+    AmountOverflow,
+    /// Amounts moved, held or captured must be greater than zero.
+    InvalidAmount,
+    InvalidCurrency,
+    CurrencyMismatch,
+    NoExchangeRate,
+    InsufficientFunds,
+    OverdraftLimitExceeded,
+    NegativeOverdraftLimit,
+    BalanceBelowOverdraftLimit,
+    AccountFrozen,
+    AccountClosed,
+    AccountAlreadyFrozen,
+    AccountNotFrozen,
+    AccountAlreadyClosed,
+    NonZeroBalance,
+    OpenHolds,
+    AccountExists,
+    HoldNotFound,
+    CaptureExceedsHold,
+    SameAccount,
+    TransferAlreadyReversed,
+    ReversalNotReversible,
+    ReceiverLacksFunds,
+    TreasuryLacksFunds,
+    InvalidDayOfMonth,
+    ScheduleEndsBeforeFirstRun,
+    ScheduleNotActive,
+    /// A journal entry whose debits and credits differ. Only a bug can cause this.
+    UnbalancedEntry,
+}
+
+impl DomainError {
+    pub fn code(self) -> &'static str {
+        match self {
+            DomainError::AmountOverflow => "amount_overflow",
+            DomainError::InvalidAmount => "invalid_amount",
+            DomainError::InvalidCurrency => "invalid_currency",
+            DomainError::CurrencyMismatch => "currency_mismatch",
+            DomainError::NoExchangeRate => "no_exchange_rate",
+            DomainError::InsufficientFunds => "insufficient_funds",
+            DomainError::OverdraftLimitExceeded => "overdraft_limit_exceeded",
+            DomainError::NegativeOverdraftLimit => "negative_overdraft_limit",
+            DomainError::BalanceBelowOverdraftLimit => "balance_below_overdraft_limit",
+            DomainError::AccountFrozen => "account_frozen",
+            DomainError::AccountClosed => "account_closed",
+            DomainError::AccountAlreadyFrozen => "account_already_frozen",
+            DomainError::AccountNotFrozen => "account_not_frozen",
+            DomainError::AccountAlreadyClosed => "account_already_closed",
+            DomainError::NonZeroBalance => "nonzero_balance",
+            DomainError::OpenHolds => "open_holds",
+            DomainError::AccountExists => "account_exists",
+            DomainError::HoldNotFound => "hold_not_found",
+            DomainError::CaptureExceedsHold => "capture_exceeds_hold",
+            DomainError::SameAccount => "same_account",
+            DomainError::TransferAlreadyReversed => "transfer_already_reversed",
+            DomainError::ReversalNotReversible => "reversal_not_reversible",
+            DomainError::ReceiverLacksFunds => "receiver_lacks_funds",
+            DomainError::TreasuryLacksFunds => "treasury_lacks_funds",
+            DomainError::InvalidDayOfMonth => "invalid_day_of_month",
+            DomainError::ScheduleEndsBeforeFirstRun => "schedule_ends_before_first_run",
+            DomainError::ScheduleNotActive => "schedule_not_active",
+            DomainError::UnbalancedEntry => "unbalanced_entry",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            DomainError::AmountOverflow => "Amount overflow.",
+            DomainError::InvalidAmount => "Amount must be positive.",
+            DomainError::InvalidCurrency => "Currency must be a three-letter uppercase code.",
+            DomainError::CurrencyMismatch => "Currency mismatch.",
+            DomainError::NoExchangeRate => "No exchange rate available for this currency pair.",
+            DomainError::InsufficientFunds => "Insufficient funds.",
+            DomainError::OverdraftLimitExceeded => "Overdraft limit exceeded.",
+            DomainError::NegativeOverdraftLimit => "Overdraft limit cannot be negative.",
+            DomainError::BalanceBelowOverdraftLimit => {
+                "Balance is already below the new overdraft limit."
+            }
+            DomainError::AccountFrozen => "Account is frozen.",
+            DomainError::AccountClosed => "Account is closed.",
+            DomainError::AccountAlreadyFrozen => "Account is already frozen.",
+            DomainError::AccountNotFrozen => "Account is not frozen.",
+            DomainError::AccountAlreadyClosed => "Account is already closed.",
+            DomainError::NonZeroBalance => "Only an account with a zero balance can be closed.",
+            DomainError::OpenHolds => "Account has open holds.",
+            DomainError::AccountExists => "Account already exists.",
+            DomainError::HoldNotFound => "Hold not found.",
+            DomainError::CaptureExceedsHold => "Capture amount exceeds the hold.",
+            DomainError::SameAccount => "Sender and receiver accounts cannot be the same.",
+            DomainError::TransferAlreadyReversed => "Transfer has already been reversed.",
+            DomainError::ReversalNotReversible => "A reversal cannot itself be reversed.",
+            DomainError::ReceiverLacksFunds => {
+                "Receiver no longer has the funds to reverse this transfer."
+            }
+            DomainError::TreasuryLacksFunds => {
+                "The treasury does not hold enough money to fund this account."
+            }
+            DomainError::InvalidDayOfMonth => "Day of month must be between 1 and 31.",
+            DomainError::ScheduleEndsBeforeFirstRun => "The schedule ends before its first run.",
+            DomainError::ScheduleNotActive => "Schedule is no longer active.",
+            DomainError::UnbalancedEntry => "Journal entry does not balance.",
+        }
+    }
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// What a `NotFound` error could not find.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Account,
+    /// The account a transfer would debit.
+    Sender,
+    /// The account a transfer would credit.
+    Receiver,
+    Transfer,
+    Schedule,
+    /// No endpoint matches the request's path and method.
+    Route,
+}
+
+/// Everything a handler can fail with. Sent to clients as an RFC 9457
+/// `application/problem+json` document whose `code` member is stable.
+#[derive(Debug)]
+pub enum ApiError {
+    Rejected(DomainError),
+    NotFound(Resource),
+    /// The account is no longer at the version named in `If-Match`.
+    VersionMismatch,
+    /// The JSON body could not be read into the endpoint's request type.
+    InvalidBody(String),
+    /// The JSON body or query string was read but breaks the request type's
+    /// rules, e.g. an amount out of range. Lists every broken rule.
+    ValidationFailed(Vec<FieldError>),
+    InvalidQuery(String),
+    /// A path segment, e.g. an account id, is malformed.
+    InvalidPath(String),
+    InvalidIfMatch,
+    /// The endpoint changes several accounts, which one `If-Match` header cannot
+    /// name the versions of.
+    IfMatchNotSupported,
+    InvalidIdempotencyKey,
+    /// A request with the same `Idempotency-Key` is still running.
+    IdempotencyKeyInUse,
+    /// The `Idempotency-Key` was first used with a different request.
+    IdempotencyKeyReused,
+    UnknownCursor,
+    /// At least one leg of a batch failed, so none was applied.
+    BatchRejected,
+    /// The route needs credentials and the request sent none.
+    Unauthenticated,
+    /// The request sent an API key or token that is unknown, malformed or expired.
+    InvalidCredentials,
+    /// The principal is known but may not use this route.
+    Forbidden,
+    /// The client used up its allowance for this route; it may try again in
+    /// `retry_after` seconds.
+    RateLimited {
+        retry_after: u64,
+    },
+    /// The server failed, e.g. to write its event log. The reason is logged on
+    /// the server; clients only learn that something went wrong.
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Rejected(e) => e.code(),
+            ApiError::NotFound(Resource::Account) => "account_not_found",
+            ApiError::NotFound(Resource::Sender) => "sender_not_found",
+            ApiError::NotFound(Resource::Receiver) => "receiver_not_found",
+            ApiError::NotFound(Resource::Transfer) => "transfer_not_found",
+            ApiError::NotFound(Resource::Schedule) => "schedule_not_found",
+            ApiError::NotFound(Resource::Route) => "route_not_found",
+            ApiError::VersionMismatch => "version_mismatch",
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::ValidationFailed(_) => "validation_failed",
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::InvalidPath(_) => "invalid_path",
+            ApiError::InvalidIfMatch => "invalid_if_match",
+            ApiError::IfMatchNotSupported => "if_match_not_supported",
+            ApiError::InvalidIdempotencyKey => "invalid_idempotency_key",
+            ApiError::IdempotencyKeyInUse => "idempotency_key_in_use",
+            ApiError::IdempotencyKeyReused => "idempotency_key_reused",
+            ApiError::UnknownCursor => "unknown_cursor",
+            ApiError::BatchRejected => "batch_rejected",
+            ApiError::Unauthenticated => "unauthenticated",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::Forbidden => "forbidden",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// The error as a problem document carrying the extra members in `extensions`,
+    /// which must serialize as a map (or as nothing, like `()`).
+    pub fn problem_response(&self, extensions: impl Serialize) -> HttpResponse {
+        let status = self.status_code();
+        let problem = Problem {
+            // Problems are told apart by `code`; there are no documentation pages to link.
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            code: self.code(),
+            extensions,
+        };
+        HttpResponse::build(status)
+            .content_type("application/problem+json")
+            .json(problem)
+    }
+
+    /// Refuses `req` from inside a middleware. The error becomes a response right
+    /// away instead of being returned, as middleware further out, like the one
+    /// adding the security headers, only sees responses, not errors.
+    pub fn refuse<B>(self, req: ServiceRequest) -> ServiceResponse<EitherBody<B>> {
+        req.error_response(self).map_into_right_body()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Rejected(e) => e.fmt(f),
+            ApiError::NotFound(Resource::Account) => f.write_str("Account not found."),
+            ApiError::NotFound(Resource::Sender) => f.write_str("Sender account not found."),
+            ApiError::NotFound(Resource::Receiver) => f.write_str("Receiver account not found."),
+            ApiError::NotFound(Resource::Transfer) => f.write_str("Transfer not found."),
+            ApiError::NotFound(Resource::Schedule) => f.write_str("Schedule not found."),
+            ApiError::NotFound(Resource::Route) => f.write_str("No such endpoint."),
+            ApiError::VersionMismatch => {
+                f.write_str("Account has changed since it was read; fetch it again and retry.")
+            }
+            ApiError::InvalidBody(reason) => write!(f, "Invalid request body: {reason}"),
+            ApiError::ValidationFailed(errors) => match errors.len() {
+                1 => f.write_str("1 field of the request is invalid."),
+                n => write!(f, "{n} fields of the request are invalid."),
+            },
+            ApiError::InvalidQuery(reason) => write!(f, "Invalid query string: {reason}"),
+            ApiError::InvalidPath(reason) => write!(f, "Invalid path: {reason}"),
+            ApiError::InvalidIfMatch => f.write_str("Invalid If-Match header."),
+            ApiError::IfMatchNotSupported => f.write_str(
+                "If-Match is not supported here, as the request changes several accounts.",
+            ),
+            ApiError::InvalidIdempotencyKey => {
+                f.write_str("Idempotency-Key must be between 1 and 255 visible ASCII characters.")
+            }
+            ApiError::IdempotencyKeyInUse => {
+                f.write_str("A request with this Idempotency-Key is still being processed.")
+            }
+            ApiError::IdempotencyKeyReused => {
+                f.write_str("Idempotency-Key was already used with a different request.")
+            }
+            ApiError::UnknownCursor => f.write_str("Unknown cursor."),
+            ApiError::BatchRejected => f.write_str("Batch rejected; no leg was applied."),
+            ApiError::Unauthenticated => f.write_str("Authentication required."),
+            ApiError::InvalidCredentials => f.write_str("Invalid API key or token."),
+            ApiError::Forbidden => f.write_str("Not allowed for this principal."),
+            ApiError::RateLimited { retry_after } => {
+                write!(f, "Too many requests; try again in {retry_after} s.")
+            }
+            ApiError::Internal(_) => f.write_str("Internal server error."),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Rejected(DomainError::HoldNotFound)
+            | ApiError::NotFound(_)
+            | ApiError::InvalidPath(_) => StatusCode::NOT_FOUND,
+            ApiError::Rejected(DomainError::AccountExists) | ApiError::IdempotencyKeyInUse => {
+                StatusCode::CONFLICT
+            }
+            ApiError::VersionMismatch => StatusCode::PRECONDITION_FAILED,
+            ApiError::Unauthenticated | ApiError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Internal(reason) = self {
+            eprintln!("⚠️  Internal error: {reason}");
+        }
+        let mut response = match self {
+            ApiError::ValidationFailed(errors) => {
+                self.problem_response(ValidationFailure { errors })
+            }
+            _ => self.problem_response(()),
+        };
+        if response.status() == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        if let ApiError::RateLimited { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(*retry_after));
+        }
+        response
+    }
+}
+
+/// Added to the problem response of a request that failed validation.
+#[derive(Serialize)]
+struct ValidationFailure<'a> {
+    errors: &'a [FieldError],
+}
+
+/// The problem document sent for an [`ApiError`]. `extensions` adds members
+/// specific to one endpoint, e.g. the per-leg results of a batch.
+#[derive(Serialize)]
+struct Problem<E> {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+    #[serde(flatten)]
+    extensions: E,
+}
+
+impl From<DomainError> for ApiError {
+    fn from(e: DomainError) -> Self {
+        ApiError::Rejected(e)
+    }
+}
+
+impl From<StoreError> for ApiError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::AccountNotFound(_) => ApiError::NotFound(Resource::Account),
+            StoreError::Rejected(e) => ApiError::Rejected(e),
+            StoreError::VersionMismatch(_) => ApiError::VersionMismatch,
+            StoreError::Backend(_) => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<ScheduleError> for ApiError {
+    fn from(e: ScheduleError) -> Self {
+        match e {
+            ScheduleError::NotFound => ApiError::NotFound(Resource::Schedule),
+            ScheduleError::Rejected(e) => ApiError::Rejected(e),
+            ScheduleError::Log(e) => ApiError::Internal(format!("Failed to record event: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    #[actix_web::test]
+    async fn a_storage_failure_is_a_500_that_keeps_its_reason_to_itself() {
+        let error = ApiError::from(StoreError::Backend("disk I/O error at /var/db".to_owned()));
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["code"], "internal_error");
+        assert_eq!(problem["detail"], "Internal server error.");
+        assert!(!String::from_utf8_lossy(&body).contains("disk"));
+    }
+}
@@ -0,0 +1,444 @@
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+const LOG_FILE: &str = "events.jsonl";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// State that can be rebuilt by replaying its events in order.
+///
+/// Events record outcomes rather than requests: replaying them must rebuild
+/// exactly the state the server had, including journal entry ids and timestamps.
+pub trait EventSourced: Default + Serialize + DeserializeOwned {
+    type Event: Serialize + DeserializeOwned;
+
+    /// Applies one event. An error means the log describes something impossible
+    /// and replay must stop.
+    fn apply(&mut self, event: Self::Event) -> Result<(), String>;
+}
+
+/// One line of the event log.
+#[derive(Serialize, Deserialize)]
+struct EventRecord<E> {
+    sequence: u64,
+    recorded_at: DateTime<Utc>,
+    event: E,
+}
+
+/// A full copy of the state after `sequence`, so replay can start at `log_offset`
+/// instead of at the beginning of the log.
+#[derive(Serialize, Deserialize)]
+struct Snapshot<S> {
+    sequence: u64,
+    log_offset: u64,
+    state: S,
+}
+
+/// An append-only event log on disk, plus periodic snapshots next to it.
+///
+/// Writing a record and making it durable are separate steps: [`EventLog::write`]
+/// runs under whatever lock guards the state, and the returned [`Pending`] is
+/// waited on after that lock is released. Records written meanwhile by other
+/// callers are made durable by the same sync, so concurrent writers share the
+/// cost of one instead of queueing for one each.
+pub struct EventLog<S> {
+    dir: PathBuf,
+    file: File,
+    sync: Arc<LogSync>,
+    /// Byte length of the log, i.e. where the next record starts.
+    offset: u64,
+    next_sequence: u64,
+    snapshot_every: u64,
+    since_snapshot: u64,
+    state: PhantomData<fn() -> S>,
+}
+
+impl<S: EventSourced> EventLog<S> {
+    /// Opens (or creates) the log in `dir` and rebuilds the state it describes,
+    /// starting from the latest snapshot if there is one.
+    pub fn open(dir: impl AsRef<Path>, snapshot_every: u64) -> io::Result<(Self, S)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let (mut state, mut sequence, mut offset) = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => {
+                let snapshot: Snapshot<S> = serde_json::from_slice(&bytes)?;
+                (snapshot.state, snapshot.sequence, snapshot.log_offset)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (S::default(), 0, 0),
+            Err(e) => return Err(e),
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE))?;
+        if file.metadata()?.len() < offset {
+            return Err(invalid_data("event log is shorter than the snapshot"));
+        }
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut replayed = 0;
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            if !line.ends_with('\n') {
+                // A record without its newline is a write torn by a crash; it never
+                // took effect, so drop it and carry on from the last complete record.
+                println!(
+                    "⚠️  Discarding incomplete event at the end of {}",
+                    dir.display()
+                );
+                file.set_len(offset)?;
+                break;
+            }
+            let record: EventRecord<S::Event> = serde_json::from_str(&line)?;
+            if record.sequence != sequence + 1 {
+                return Err(invalid_data(format!(
+                    "expected event {} but found {}",
+                    sequence + 1,
+                    record.sequence
+                )));
+            }
+            state
+                .apply(record.event)
+                .map_err(|e| invalid_data(format!("event {}: {e}", record.sequence)))?;
+            sequence = record.sequence;
+            offset += read as u64;
+            replayed += 1;
+        }
+
+        println!(
+            "📜 Replayed {replayed} events from {} (state is at event {sequence})",
+            dir.display()
+        );
+
+        let log = Self {
+            dir,
+            sync: Arc::new(LogSync {
+                file: file.try_clone()?,
+                state: Mutex::new(SyncState {
+                    written: sequence,
+                    synced: sequence,
+                    syncing: false,
+                    failed: None,
+                }),
+                done: Condvar::new(),
+            }),
+            file,
+            offset,
+            next_sequence: sequence + 1,
+            snapshot_every,
+            since_snapshot: replayed,
+            state: PhantomData,
+        };
+        Ok((log, state))
+    }
+
+    /// Durably appends `event` to the log. Callers must only apply the event
+    /// once this has succeeded.
+    pub fn append(&mut self, event: &S::Event) -> io::Result<()> {
+        self.write(event)?.wait()
+    }
+
+    /// Appends `event` to the log without waiting for it to reach the disk.
+    /// Events are applied in the order they are written, so callers apply it
+    /// right away, under the same lock, and report success once the returned
+    /// [`Pending`] is durable.
+    pub fn write(&mut self, event: &S::Event) -> io::Result<Pending> {
+        if let Some(reason) = &self.sync.state().failed {
+            return Err(io::Error::other(reason.clone()));
+        }
+        let record = EventRecord {
+            sequence: self.next_sequence,
+            recorded_at: Utc::now(),
+            event,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        if let Err(e) = self.file.write_all(&line) {
+            // Cut off whatever part of the record made it, so the next one
+            // does not start in the middle of a line.
+            let _ = self.file.set_len(self.offset);
+            return Err(e);
+        }
+
+        self.offset += line.len() as u64;
+        self.since_snapshot += 1;
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.sync.state().written = sequence;
+        Ok(Pending {
+            sync: Arc::clone(&self.sync),
+            sequence,
+        })
+    }
+
+    /// Writes a snapshot if enough events have been logged since the last one.
+    /// `state` must reflect every event appended so far. The file is replaced
+    /// atomically, and a failure leaves the previous snapshot in place.
+    pub fn snapshot_if_due(&mut self, state: &S) -> io::Result<()> {
+        if self.snapshot_every == 0 || self.since_snapshot < self.snapshot_every {
+            return Ok(());
+        }
+
+        // The snapshot points past every record written so far, so they must be
+        // on disk before it is.
+        self.sync.sync_through(self.next_sequence - 1)?;
+        let snapshot = Snapshot {
+            sequence: self.next_sequence - 1,
+            log_offset: self.offset,
+            state,
+        };
+        let tmp_path = self.dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+        let mut tmp = File::create(&tmp_path)?;
+        serde_json::to_writer(&mut tmp, &snapshot)?;
+        tmp.sync_all()?;
+        fs::rename(tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+
+        self.since_snapshot = 0;
+        Ok(())
+    }
+}
+
+/// An event that is in the log but may not have reached the disk yet.
+#[must_use = "the event is not durable until waited on"]
+pub struct Pending {
+    sync: Arc<LogSync>,
+    sequence: u64,
+}
+
+impl Pending {
+    /// Blocks until the event is durable. Must not be called while holding the
+    /// lock that guards [`EventLog::write`], or nobody else can write meanwhile.
+    pub fn wait(self) -> io::Result<()> {
+        self.sync.sync_through(self.sequence)
+    }
+}
+
+/// Group commit: one caller at a time syncs the log, on behalf of everyone whose
+/// record was written before it started.
+struct LogSync {
+    /// The log file, opened a second time so it can be synced without the lock
+    /// guarding writes to it.
+    file: File,
+    state: Mutex<SyncState>,
+    done: Condvar,
+}
+
+struct SyncState {
+    /// The last sequence number written to the file.
+    written: u64,
+    /// The last sequence number known to be on disk.
+    synced: u64,
+    /// Whether someone is syncing right now.
+    syncing: bool,
+    /// Set once a sync failed. Which records reached the disk is unknown after
+    /// that, so the log takes no more writes; a restart replays what survived.
+    failed: Option<String>,
+}
+
+impl LogSync {
+    fn state(&self) -> std::sync::MutexGuard<'_, SyncState> {
+        self.state.lock().unwrap()
+    }
+
+    fn sync_through(&self, sequence: u64) -> io::Result<()> {
+        let mut state = self.state();
+        loop {
+            if let Some(reason) = &state.failed {
+                return Err(io::Error::other(reason.clone()));
+            }
+            if state.synced >= sequence {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.done.wait(state).unwrap();
+        }
+
+        state.syncing = true;
+        let target = state.written;
+        drop(state);
+        let result = self.file.sync_data();
+        let mut state = self.state();
+        state.syncing = false;
+        match &result {
+            Ok(()) => state.synced = state.synced.max(target),
+            Err(e) => state.failed = Some(format!("failed to sync the event log: {e}")),
+        }
+        self.done.notify_all();
+        result
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// The numbers seen so far, and how many of them were replayed on open.
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct Tally {
+        numbers: Vec<u32>,
+        #[serde(skip)]
+        replayed: usize,
+    }
+
+    impl EventSourced for Tally {
+        type Event = u32;
+
+        fn apply(&mut self, number: u32) -> Result<(), String> {
+            if number == 0 {
+                return Err("zero is not allowed".to_owned());
+            }
+            self.numbers.push(number);
+            self.replayed += 1;
+            Ok(())
+        }
+    }
+
+    /// Logs and applies each number, the way the stores do.
+    fn record(log: &mut EventLog<Tally>, tally: &mut Tally, numbers: &[u32]) {
+        for &number in numbers {
+            log.append(&number).unwrap();
+            tally.apply(number).unwrap();
+            log.snapshot_if_due(tally).unwrap();
+        }
+    }
+
+    #[test]
+    fn reopening_replays_every_event() {
+        let dir = TempDir::new().unwrap();
+        let (mut log, mut tally) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        record(&mut log, &mut tally, &[1, 2, 3]);
+        drop(log);
+
+        let (_, reopened) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        assert_eq!(reopened.numbers, [1, 2, 3]);
+        assert_eq!(reopened.replayed, 3);
+        assert!(!dir.path().join(SNAPSHOT_FILE).exists());
+    }
+
+    #[test]
+    fn reopening_starts_from_the_snapshot_and_replays_the_tail() {
+        let dir = TempDir::new().unwrap();
+        let (mut log, mut tally) = EventLog::<Tally>::open(dir.path(), 2).unwrap();
+        record(&mut log, &mut tally, &[1, 2, 3]);
+        drop(log);
+
+        let (mut log, mut reopened) = EventLog::<Tally>::open(dir.path(), 2).unwrap();
+        assert_eq!(reopened.numbers, [1, 2, 3]);
+        // Events 1 and 2 came from the snapshot.
+        assert_eq!(reopened.replayed, 1);
+
+        // The sequence carries on where it left off.
+        record(&mut log, &mut reopened, &[4]);
+        drop(log);
+        let (_, reopened) = EventLog::<Tally>::open(dir.path(), 2).unwrap();
+        assert_eq!(reopened.numbers, [1, 2, 3, 4]);
+        assert_eq!(reopened.replayed, 0);
+    }
+
+    #[test]
+    fn a_torn_last_record_is_dropped() {
+        let dir = TempDir::new().unwrap();
+        let (mut log, mut tally) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        record(&mut log, &mut tally, &[1, 2]);
+        drop(log);
+        let path = dir.path().join(LOG_FILE);
+        let complete = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(br#"{"sequence":3,"recorded_at":"2026-"#)
+            .unwrap();
+
+        let (mut log, mut reopened) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        assert_eq!(reopened.numbers, [1, 2]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+
+        // The torn event's sequence number is used again by the next one.
+        record(&mut log, &mut reopened, &[3]);
+        drop(log);
+        let (_, reopened) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        assert_eq!(reopened.numbers, [1, 2, 3]);
+    }
+
+    #[test]
+    fn a_gap_in_the_sequence_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let (mut log, mut tally) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        record(&mut log, &mut tally, &[1]);
+        log.next_sequence += 1;
+        record(&mut log, &mut tally, &[2]);
+        drop(log);
+
+        let error = EventLog::<Tally>::open(dir.path(), 0).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn an_event_the_state_refuses_stops_replay() {
+        let dir = TempDir::new().unwrap();
+        let (mut log, _) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        log.append(&0).unwrap();
+        drop(log);
+
+        let error = EventLog::<Tally>::open(dir.path(), 0).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("zero is not allowed"));
+    }
+
+    #[test]
+    fn one_sync_covers_every_record_written_before_it() {
+        let dir = TempDir::new().unwrap();
+        let (mut log, _) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        let first = log.write(&1).unwrap();
+        let second = log.write(&2).unwrap();
+        assert_eq!(log.sync.state().synced, 0);
+
+        second.wait().unwrap();
+        assert_eq!(log.sync.state().synced, 2);
+        first.wait().unwrap();
+
+        drop(log);
+        let (_, reopened) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        assert_eq!(reopened.numbers, [1, 2]);
+    }
+
+    #[test]
+    fn a_failed_sync_stops_the_log() {
+        let dir = TempDir::new().unwrap();
+        let (mut log, _) = EventLog::<Tally>::open(dir.path(), 0).unwrap();
+        let pending = log.write(&1).unwrap();
+        log.sync.state().failed = Some("failed to sync the event log: disk on fire".to_owned());
+
+        assert!(
+            pending
+                .wait()
+                .unwrap_err()
+                .to_string()
+                .contains("disk on fire")
+        );
+        assert!(log.write(&2).is_err());
+        assert!(log.append(&2).is_err());
+    }
+}
@@ -0,0 +1,462 @@
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::events::{EventLog, EventSourced};
+use actix_web::body::{self, BoxBody};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, ResponseError, web};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Set on a response that was replayed from the cache rather than produced anew.
+const REPLAYED: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Which endpoint a key was used with, who used it, plus the key itself. The
+/// same key sent to two different endpoints, or by two different principals,
+/// names two different requests.
+type CacheKey = (String, Option<String>, String);
+
+/// A response as it was first sent, kept so a retry gets exactly the same one.
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Every endpoint answers with JSON, so the body is always text.
+    body: String,
+}
+
+impl StoredResponse {
+    fn replay(&self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).expect("checked when recorded");
+        let mut response = HttpResponse::build(status);
+        for (name, value) in &self.headers {
+            response.append_header((name.as_str(), value.as_str()));
+        }
+        response
+            .insert_header((
+                HeaderName::from_static(REPLAYED),
+                HeaderValue::from_static("true"),
+            ))
+            .body(self.body.clone())
+    }
+}
+
+/// A request that ran to completion, as written to the log.
+#[derive(Clone, Serialize, Deserialize)]
+struct Completion {
+    scope: String,
+    subject: Option<String>,
+    key: String,
+    fingerprint: String,
+    response: StoredResponse,
+    expires_at: DateTime<Utc>,
+}
+
+impl Completion {
+    fn cache_key(&self) -> CacheKey {
+        (self.scope.clone(), self.subject.clone(), self.key.clone())
+    }
+}
+
+enum Entry {
+    /// The first request with this key is still being handled.
+    InFlight,
+    Done(Completion),
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<CacheKey, Entry>,
+    /// Completed keys in the order they expire, so purging never scans the map.
+    expiry: VecDeque<(DateTime<Utc>, CacheKey)>,
+}
+
+impl Entries {
+    fn purge_expired(&mut self, now: DateTime<Utc>) {
+        while let Some((expires_at, _)) = self.expiry.front() {
+            if *expires_at > now {
+                break;
+            }
+            let (expires_at, key) = self.expiry.pop_front().expect("front was just checked");
+            // The key may have been reused since; only drop the entry this record is for.
+            if let Some(Entry::Done(done)) = self.by_key.get(&key)
+                && done.expires_at == expires_at
+            {
+                self.by_key.remove(&key);
+            }
+        }
+    }
+
+    fn complete(&mut self, completion: Completion) {
+        let key = completion.cache_key();
+        self.expiry.push_back((completion.expires_at, key.clone()));
+        self.by_key.insert(key, Entry::Done(completion));
+    }
+}
+
+/// Changes to the cache, as written to its event log.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    Completed(Completion),
+}
+
+impl EventSourced for Entries {
+    type Event = Event;
+
+    fn apply(&mut self, event: Event) -> Result<(), String> {
+        match event {
+            Event::Completed(completion) => {
+                if StatusCode::from_u16(completion.response.status).is_err() {
+                    return Err(format!("invalid status {}", completion.response.status));
+                }
+                self.complete(completion);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Snapshots hold the completed requests only; nothing is in flight after a restart.
+impl Serialize for Entries {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.by_key.values().filter_map(|entry| match entry {
+            Entry::InFlight => None,
+            Entry::Done(done) => Some(done),
+        }))
+    }
+}
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut completions = Vec::<Completion>::deserialize(deserializer)?;
+        completions.sort_by_key(|completion| completion.expires_at);
+        let mut entries = Entries::default();
+        for completion in completions {
+            entries.complete(completion);
+        }
+        Ok(entries)
+    }
+}
+
+struct Inner {
+    entries: Entries,
+    log: EventLog<Entries>,
+}
+
+/// Remembers the responses to requests sent with an `Idempotency-Key` header,
+/// so that a retried request is answered from the cache instead of running again.
+///
+/// Completed responses are written to an event log before they are sent, so a
+/// retry after a restart is still answered from the cache. Entries are
+/// forgotten after `window`.
+pub struct IdempotencyCache {
+    inner: Mutex<Inner>,
+    window: Duration,
+}
+
+/// Removes an in-flight entry if the handler never got to store its response,
+/// so that the key can be retried.
+struct Claim<'a> {
+    cache: &'a IdempotencyCache,
+    key: Option<CacheKey>,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.cache.inner.lock().unwrap().entries.by_key.remove(&key);
+        }
+    }
+}
+
+impl IdempotencyCache {
+    pub fn open(
+        dir: impl AsRef<Path>,
+        window: Duration,
+        snapshot_every: u64,
+    ) -> std::io::Result<Self> {
+        let (log, entries) = EventLog::open(dir, snapshot_every)?;
+        Ok(Self {
+            inner: Mutex::new(Inner { entries, log }),
+            window,
+        })
+    }
+
+    /// Awaits `handler` unless `req` repeats an earlier request with the same
+    /// `Idempotency-Key`, in which case the earlier response is replayed.
+    ///
+    /// `scope` names the endpoint, and `fingerprint` must describe the request
+    /// body: reusing a key with a different body is refused rather than
+    /// silently answered with the other request's response. Requests without a
+    /// key always run. Errors returned by `handler` are remembered like any other
+    /// response, except server errors, so those can be retried.
+    pub async fn run(
+        &self,
+        req: &HttpRequest,
+        scope: &'static str,
+        fingerprint: String,
+        handler: impl Future<Output = Result<HttpResponse, ApiError>>,
+    ) -> Result<HttpResponse, ApiError> {
+        let key = match req.headers().get(IDEMPOTENCY_KEY) {
+            None => return handler.await,
+            Some(value) => match value.to_str() {
+                Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_owned(),
+                _ => return Err(ApiError::InvalidIdempotencyKey),
+            },
+        };
+        let subject = req
+            .extensions()
+            .get::<Principal>()
+            .map(|principal| principal.subject.clone());
+        let key = (scope.to_owned(), subject, key);
+
+        let mut claim = {
+            let mut inner = self.inner.lock().unwrap();
+            let entries = &mut inner.entries;
+            let now = Utc::now();
+            entries.purge_expired(now);
+            match entries.by_key.get(&key) {
+                Some(Entry::InFlight) => return Err(ApiError::IdempotencyKeyInUse),
+                // A shorter window since the entry was recorded can leave it
+                // behind unexpired ones in the queue; it is gone all the same.
+                Some(Entry::Done(done)) if done.expires_at > now => {
+                    return if done.fingerprint == fingerprint {
+                        Ok(done.response.replay())
+                    } else {
+                        Err(ApiError::IdempotencyKeyReused)
+                    };
+                }
+                _ => {
+                    entries.by_key.insert(key.clone(), Entry::InFlight);
+                    Claim {
+                        cache: self,
+                        key: Some(key),
+                    }
+                }
+            }
+        };
+
+        let response = handler.await.unwrap_or_else(|e| e.error_response());
+        if response.status().is_server_error() {
+            // Dropping the claim frees the key for a retry.
+            return Ok(response);
+        }
+
+        // The body has to be read out to be stored, then put back for this response.
+        let (response, body) = response.into_parts();
+        let body = match body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(_) => {
+                return Err(ApiError::Internal(
+                    "Failed to read response body.".to_owned(),
+                ));
+            }
+        };
+        let stored = StoredResponse {
+            status: response.status().as_u16(),
+            headers: response
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                })
+                .collect(),
+            body: String::from_utf8_lossy(&body).into_owned(),
+        };
+
+        let (scope, subject, key) = claim.key.take().expect("the claim is only taken here");
+        let completion = Completion {
+            scope,
+            subject,
+            key,
+            fingerprint,
+            response: stored,
+            expires_at: chrono::Duration::from_std(self.window)
+                .ok()
+                .and_then(|window| Utc::now().checked_add_signed(window))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        };
+        let pending = {
+            let mut inner = self.inner.lock().unwrap();
+            let pending = inner.log.write(&Event::Completed(completion.clone()));
+            inner.entries.complete(completion);
+            let Inner { entries, log } = &mut *inner;
+            if let Err(e) = log.snapshot_if_due(entries) {
+                eprintln!("⚠️  Failed to write snapshot: {e}");
+            }
+            pending
+        };
+        // The request has already taken effect, so a failed write cannot undo it;
+        // the response is still remembered until the server restarts.
+        let recorded = match pending {
+            Ok(pending) => web::block(move || pending.wait())
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e.to_string()))),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = recorded {
+            eprintln!("⚠️  Failed to record idempotent response: {e}");
+        }
+
+        Ok(response.set_body(BoxBody::new(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use std::cell::Cell;
+    use tempfile::TempDir;
+
+    const WINDOW: Duration = Duration::from_secs(3600);
+
+    fn with_key(key: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((IDEMPOTENCY_KEY, key))
+            .to_http_request()
+    }
+
+    /// Runs a request through `cache`, counting in `runs` whether the handler ran.
+    async fn send(
+        cache: &IdempotencyCache,
+        req: &HttpRequest,
+        fingerprint: &str,
+        runs: &Cell<u32>,
+        outcome: Result<&str, ApiError>,
+    ) -> Result<(HttpResponse, String), ApiError> {
+        let response = cache
+            .run(req, "test", fingerprint.to_owned(), async {
+                runs.set(runs.get() + 1);
+                outcome.map(|body| HttpResponse::Created().body(body.to_owned()))
+            })
+            .await?;
+        let (response, body) = response.into_parts();
+        let body = body::to_bytes(body).await.unwrap();
+        Ok((
+            response.set_body(BoxBody::new(())),
+            String::from_utf8(body.to_vec()).unwrap(),
+        ))
+    }
+
+    fn replayed(response: &HttpResponse) -> bool {
+        response.headers().contains_key(REPLAYED)
+    }
+
+    #[actix_web::test]
+    async fn the_same_key_and_body_replay_the_first_response() {
+        let dir = TempDir::new().unwrap();
+        let cache = IdempotencyCache::open(dir.path(), WINDOW, 0).unwrap();
+        let runs = Cell::new(0);
+        let req = with_key("k1");
+
+        let (first, body) = send(&cache, &req, "a", &runs, Ok("first")).await.unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(!replayed(&first));
+
+        let (second, body_again) = send(&cache, &req, "a", &runs, Ok("second")).await.unwrap();
+        assert_eq!(runs.get(), 1);
+        assert_eq!(second.status(), StatusCode::CREATED);
+        assert!(replayed(&second));
+        assert_eq!(body_again, body);
+    }
+
+    #[actix_web::test]
+    async fn the_same_key_with_another_body_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let cache = IdempotencyCache::open(dir.path(), WINDOW, 0).unwrap();
+        let runs = Cell::new(0);
+        let req = with_key("k1");
+
+        send(&cache, &req, "a", &runs, Ok("first")).await.unwrap();
+        let error = send(&cache, &req, "b", &runs, Ok("second"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, ApiError::IdempotencyKeyReused));
+        assert_eq!(runs.get(), 1);
+    }
+
+    #[actix_web::test]
+    async fn client_errors_are_remembered_but_server_errors_are_not() {
+        let dir = TempDir::new().unwrap();
+        let cache = IdempotencyCache::open(dir.path(), WINDOW, 0).unwrap();
+        let runs = Cell::new(0);
+
+        let failed = with_key("failed");
+        let (first, _) = send(
+            &cache,
+            &failed,
+            "a",
+            &runs,
+            Err(ApiError::Internal("boom".into())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(first.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let (retry, body) = send(&cache, &failed, "a", &runs, Ok("retried"))
+            .await
+            .unwrap();
+        assert_eq!(runs.get(), 2);
+        assert!(!replayed(&retry));
+        assert_eq!(body, "retried");
+
+        let refused = with_key("refused");
+        send(&cache, &refused, "a", &runs, Err(ApiError::Forbidden))
+            .await
+            .unwrap();
+        let (again, _) = send(&cache, &refused, "a", &runs, Ok("ok")).await.unwrap();
+        assert_eq!(runs.get(), 3);
+        assert!(replayed(&again));
+        assert_eq!(again.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn requests_without_a_key_always_run() {
+        let dir = TempDir::new().unwrap();
+        let cache = IdempotencyCache::open(dir.path(), WINDOW, 0).unwrap();
+        let runs = Cell::new(0);
+        let req = TestRequest::default().to_http_request();
+        send(&cache, &req, "a", &runs, Ok("one")).await.unwrap();
+        send(&cache, &req, "a", &runs, Ok("two")).await.unwrap();
+        assert_eq!(runs.get(), 2);
+    }
+
+    #[actix_web::test]
+    async fn responses_survive_a_restart() {
+        // Once replayed from the log alone, once from a snapshot.
+        for snapshot_every in [0, 1] {
+            let dir = TempDir::new().unwrap();
+            let runs = Cell::new(0);
+            let req = with_key("k1");
+            let cache = IdempotencyCache::open(dir.path(), WINDOW, snapshot_every).unwrap();
+            send(&cache, &req, "a", &runs, Ok("first")).await.unwrap();
+            drop(cache);
+            assert_eq!(
+                dir.path().join("snapshot.json").exists(),
+                snapshot_every == 1
+            );
+
+            let cache = IdempotencyCache::open(dir.path(), WINDOW, snapshot_every).unwrap();
+            let (response, body) = send(&cache, &req, "a", &runs, Ok("again")).await.unwrap();
+            assert_eq!(runs.get(), 1);
+            assert!(replayed(&response));
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert_eq!(body, "first");
+
+            let error = send(&cache, &req, "b", &runs, Ok("other"))
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(error, ApiError::IdempotencyKeyReused));
+        }
+    }
+}
@@ -0,0 +1,507 @@
+use crate::currency::Currency;
+use crate::error::DomainError;
+use crate::money::Money;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// An account in the general ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum LedgerAccount {
+    /// A customer's secure bank account. Its balance is credits minus debits.
+    Customer(Uuid),
+    /// Where money enters or leaves the system from outside, e.g. newly minted
+    /// money, deposits and withdrawals.
+    Funding,
+    /// The bank's own position while converting between currencies.
+    FxClearing,
+    /// Where captured holds leave the bank, e.g. towards a card merchant.
+    Settlement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Debit,
+    Credit,
+}
+
+/// One line of a journal entry. Amounts are always positive; the side says
+/// which way the money moves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Posting {
+    pub account: LedgerAccount,
+    pub side: Side,
+    pub amount: Money,
+    pub currency: Currency,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    AccountOpening,
+    /// New money minted into a treasury account.
+    Issuance,
+    Deposit,
+    Withdrawal,
+    Transfer,
+    HoldCapture,
+    /// Undoes a transfer by moving the same amounts back.
+    Reversal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: Uuid,
+    pub kind: EntryKind,
+    pub recorded_at: DateTime<Utc>,
+    pub postings: Vec<Posting>,
+    /// For a reversal, the transfer it undoes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverses: Option<Uuid>,
+}
+
+impl JournalEntry {
+    /// Builds a new entry, checking that it balances per currency
+    /// (total debits equal total credits).
+    pub fn new(kind: EntryKind, postings: Vec<Posting>) -> Result<Self, DomainError> {
+        let mut net: HashMap<Currency, Money> = HashMap::new();
+        for posting in &postings {
+            if !posting.amount.is_positive() {
+                return Err(DomainError::InvalidAmount);
+            }
+            let total = net.entry(posting.currency).or_insert(Money::ZERO);
+            *total = match posting.side {
+                Side::Debit => total.checked_add(posting.amount)?,
+                Side::Credit => total.checked_sub(posting.amount)?,
+            };
+        }
+        if net.values().any(|&total| total != Money::ZERO) {
+            return Err(DomainError::UnbalancedEntry);
+        }
+
+        Ok(Self {
+            id: Uuid::new_v4(),
+            kind,
+            recorded_at: Utc::now(),
+            postings,
+            reverses: None,
+        })
+    }
+}
+
+/// Which way money moved from the point of view of one account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// One journal entry as seen from a single customer account.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountLeg {
+    /// The id of the journal entry this leg belongs to.
+    pub id: Uuid,
+    pub kind: EntryKind,
+    pub direction: Direction,
+    /// The other customer account in a transfer; `None` for money from outside.
+    pub counterparty: Option<Uuid>,
+    pub amount: Money,
+    pub currency: Currency,
+    /// The account's balance right after this leg was posted.
+    pub running_balance: Money,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// The two postings that move `amount` out of `from` and into `to`:
+/// a debit on `from` and a matching credit on `to`.
+///
+/// A negative amount moves money the other way, so the postings themselves
+/// always carry positive amounts. A zero amount produces no postings.
+pub fn movement(
+    from: LedgerAccount,
+    to: LedgerAccount,
+    amount: Money,
+    currency: Currency,
+) -> Result<Vec<Posting>, DomainError> {
+    let (from, to, amount) = if amount.is_negative() {
+        (to, from, Money::ZERO.checked_sub(amount)?)
+    } else {
+        (from, to, amount)
+    };
+    if amount == Money::ZERO {
+        return Ok(Vec::new());
+    }
+    Ok(vec![
+        Posting {
+            account: from,
+            side: Side::Debit,
+            amount,
+            currency,
+        },
+        Posting {
+            account: to,
+            side: Side::Credit,
+            amount,
+            currency,
+        },
+    ])
+}
+
+/// The postings for a secure transfer between two customer accounts.
+///
+/// A same-currency transfer is a single debit/credit pair. A cross-currency
+/// transfer goes through [`LedgerAccount::FxClearing`] so that each currency
+/// balances on its own.
+pub fn transfer_postings(
+    from: Uuid,
+    debited: Money,
+    debited_currency: Currency,
+    to: Uuid,
+    credited: Money,
+    credited_currency: Currency,
+) -> Result<Vec<Posting>, DomainError> {
+    let (from, to) = (LedgerAccount::Customer(from), LedgerAccount::Customer(to));
+    if debited_currency == credited_currency {
+        return movement(from, to, debited, debited_currency);
+    }
+    let mut postings = movement(from, LedgerAccount::FxClearing, debited, debited_currency)?;
+    postings.extend(movement(
+        LedgerAccount::FxClearing,
+        to,
+        credited,
+        credited_currency,
+    )?);
+    Ok(postings)
+}
+
+/// An append-only double-entry journal backing the secure account store.
+///
+/// Entries are validated when they are built by [`JournalEntry::new`], so money
+/// can only ever move between ledger accounts, never appear or vanish. Building
+/// and appending are separate steps so an entry can be written to the event log
+/// before it takes effect.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Ledger {
+    entries: Vec<JournalEntry>,
+}
+
+impl Ledger {
+    /// Rebuilds a ledger from entries previously taken from [`Ledger::entries`].
+    pub fn from_entries(entries: Vec<JournalEntry>) -> Self {
+        Self { entries }
+    }
+
+    pub fn append(&mut self, entry: JournalEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, id: Uuid) -> Option<&JournalEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// The id of the entry reversing `id`, if it has been reversed.
+    pub fn reversal_of(&self, id: Uuid) -> Option<Uuid> {
+        self.entries
+            .iter()
+            .find(|entry| entry.reverses == Some(id))
+            .map(|entry| entry.id)
+    }
+
+    /// Every entry touching the customer account `id` in `currency`, oldest first,
+    /// with the running balance after each one.
+    pub fn account_history(
+        &self,
+        id: Uuid,
+        currency: Currency,
+    ) -> Result<Vec<AccountLeg>, DomainError> {
+        let account = LedgerAccount::Customer(id);
+        let mut running_balance = Money::ZERO;
+        let mut legs = Vec::new();
+
+        for entry in &self.entries {
+            // Net effect of this entry on the account: credits minus debits.
+            let mut net = Money::ZERO;
+            let mut touched = false;
+            for posting in &entry.postings {
+                if posting.account != account || posting.currency != currency {
+                    continue;
+                }
+                touched = true;
+                net = match posting.side {
+                    Side::Credit => net.checked_add(posting.amount)?,
+                    Side::Debit => net.checked_sub(posting.amount)?,
+                };
+            }
+            if !touched {
+                continue;
+            }
+
+            running_balance = running_balance.checked_add(net)?;
+            let counterparty = entry
+                .postings
+                .iter()
+                .find_map(|posting| match posting.account {
+                    LedgerAccount::Customer(other) if other != id => Some(other),
+                    _ => None,
+                });
+            let (direction, amount) = if net.is_negative() {
+                (Direction::Outgoing, Money::ZERO.checked_sub(net)?)
+            } else {
+                (Direction::Incoming, net)
+            };
+            legs.push(AccountLeg {
+                id: entry.id,
+                kind: entry.kind,
+                direction,
+                counterparty,
+                amount,
+                currency,
+                running_balance,
+                recorded_at: entry.recorded_at,
+            });
+        }
+
+        Ok(legs)
+    }
+
+    /// The balance of `account` in `currency` as derived from the journal:
+    /// credits minus debits, which is how a customer account's balance reads.
+    pub fn balance(
+        &self,
+        account: LedgerAccount,
+        currency: Currency,
+    ) -> Result<Money, DomainError> {
+        self.entries
+            .iter()
+            .flat_map(|entry| &entry.postings)
+            .filter(|posting| posting.account == account && posting.currency == currency)
+            .try_fold(Money::ZERO, |balance, posting| match posting.side {
+                Side::Credit => balance.checked_add(posting.amount),
+                Side::Debit => balance.checked_sub(posting.amount),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd(minor: i64) -> Money {
+        Money::from_minor_units(minor)
+    }
+
+    fn posting(account: LedgerAccount, side: Side, amount: Money, currency: Currency) -> Posting {
+        Posting {
+            account,
+            side,
+            amount,
+            currency,
+        }
+    }
+
+    #[test]
+    fn a_balanced_entry_is_accepted() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let postings = movement(
+            LedgerAccount::Customer(a),
+            LedgerAccount::Customer(b),
+            usd(500),
+            Currency::DEFAULT,
+        )
+        .unwrap();
+        let entry = JournalEntry::new(EntryKind::Transfer, postings).unwrap();
+        assert_eq!(entry.postings.len(), 2);
+        assert_eq!(entry.reverses, None);
+    }
+
+    #[test]
+    fn an_unbalanced_entry_is_refused() {
+        let postings = vec![
+            posting(
+                LedgerAccount::Funding,
+                Side::Debit,
+                usd(500),
+                Currency::DEFAULT,
+            ),
+            posting(
+                LedgerAccount::Customer(Uuid::new_v4()),
+                Side::Credit,
+                usd(499),
+                Currency::DEFAULT,
+            ),
+        ];
+        assert_eq!(
+            JournalEntry::new(EntryKind::Deposit, postings).unwrap_err(),
+            DomainError::UnbalancedEntry
+        );
+    }
+
+    #[test]
+    fn an_entry_must_balance_in_each_currency_on_its_own() {
+        let eur: Currency = "EUR".parse().unwrap();
+        let postings = vec![
+            posting(
+                LedgerAccount::Customer(Uuid::new_v4()),
+                Side::Debit,
+                usd(500),
+                Currency::DEFAULT,
+            ),
+            posting(
+                LedgerAccount::Customer(Uuid::new_v4()),
+                Side::Credit,
+                usd(500),
+                eur,
+            ),
+        ];
+        assert_eq!(
+            JournalEntry::new(EntryKind::Transfer, postings).unwrap_err(),
+            DomainError::UnbalancedEntry
+        );
+
+        let postings = transfer_postings(
+            Uuid::new_v4(),
+            usd(500),
+            Currency::DEFAULT,
+            Uuid::new_v4(),
+            usd(460),
+            eur,
+        )
+        .unwrap();
+        assert!(JournalEntry::new(EntryKind::Transfer, postings).is_ok());
+    }
+
+    #[test]
+    fn postings_must_be_positive() {
+        for amount in [usd(0), usd(-500)] {
+            let postings = vec![
+                posting(
+                    LedgerAccount::Funding,
+                    Side::Debit,
+                    amount,
+                    Currency::DEFAULT,
+                ),
+                posting(
+                    LedgerAccount::Customer(Uuid::new_v4()),
+                    Side::Credit,
+                    amount,
+                    Currency::DEFAULT,
+                ),
+            ];
+            assert_eq!(
+                JournalEntry::new(EntryKind::Deposit, postings).unwrap_err(),
+                DomainError::InvalidAmount
+            );
+        }
+    }
+
+    fn entry(kind: EntryKind, postings: Result<Vec<Posting>, DomainError>) -> JournalEntry {
+        JournalEntry::new(kind, postings.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn account_history_keeps_a_running_balance_with_directions_and_counterparties() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let (alice_account, bob_account) =
+            (LedgerAccount::Customer(alice), LedgerAccount::Customer(bob));
+        let mut ledger = Ledger::default();
+        ledger.append(entry(
+            EntryKind::Deposit,
+            movement(
+                LedgerAccount::Funding,
+                alice_account,
+                usd(1_000),
+                Currency::DEFAULT,
+            ),
+        ));
+        ledger.append(entry(
+            EntryKind::Transfer,
+            movement(alice_account, bob_account, usd(300), Currency::DEFAULT),
+        ));
+        ledger.append(entry(
+            EntryKind::Transfer,
+            movement(bob_account, alice_account, usd(50), Currency::DEFAULT),
+        ));
+
+        let history = ledger.account_history(alice, Currency::DEFAULT).unwrap();
+        let summary: Vec<_> = history
+            .iter()
+            .map(|leg| {
+                (
+                    leg.direction,
+                    leg.amount,
+                    leg.counterparty,
+                    leg.running_balance,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Direction::Incoming, usd(1_000), None, usd(1_000)),
+                (Direction::Outgoing, usd(300), Some(bob), usd(700)),
+                (Direction::Incoming, usd(50), Some(bob), usd(750)),
+            ]
+        );
+        assert_eq!(
+            ledger.balance(alice_account, Currency::DEFAULT).unwrap(),
+            usd(750)
+        );
+    }
+
+    #[test]
+    fn account_history_only_shows_the_requested_currency() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let eur: Currency = "EUR".parse().unwrap();
+        let mut ledger = Ledger::default();
+        ledger.append(entry(
+            EntryKind::Transfer,
+            transfer_postings(alice, usd(500), Currency::DEFAULT, bob, usd(460), eur),
+        ));
+
+        let alice_history = ledger.account_history(alice, Currency::DEFAULT).unwrap();
+        assert_eq!(alice_history.len(), 1);
+        assert_eq!(alice_history[0].direction, Direction::Outgoing);
+        assert_eq!(alice_history[0].running_balance, usd(-500));
+        assert!(ledger.account_history(alice, eur).unwrap().is_empty());
+
+        let bob_history = ledger.account_history(bob, eur).unwrap();
+        assert_eq!(bob_history.len(), 1);
+        assert_eq!(bob_history[0].amount, usd(460));
+        assert_eq!(bob_history[0].counterparty, Some(alice));
+        assert!(
+            ledger
+                .account_history(bob, Currency::DEFAULT)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn account_history_of_an_untouched_account_is_empty() {
+        let mut ledger = Ledger::default();
+        ledger.append(entry(
+            EntryKind::Deposit,
+            movement(
+                LedgerAccount::Funding,
+                LedgerAccount::Customer(Uuid::new_v4()),
+                usd(100),
+                Currency::DEFAULT,
+            ),
+        ));
+        assert!(
+            ledger
+                .account_history(Uuid::new_v4(), Currency::DEFAULT)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
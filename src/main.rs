@@ -1,8 +1,43 @@
-use actix_web::{App, HttpResponse, HttpServer, Responder, web};
+use actix_web::body::MessageBody;
+use actix_web::http::header::{self, ETag, EntityTag, IfMatch};
+use actix_web::middleware::{Condition, DefaultHeaders, from_fn};
+use actix_web::{App, HttpMessage, HttpRequest, HttpResponse, HttpServer, web};
+use auth::{Authenticator, Principal};
+use chrono::{DateTime, Utc};
+use currency::{Currency, ExchangeRate, RateTable};
+use error::{ApiError, DomainError, Resource};
+use events::EventLog;
+use idempotency::IdempotencyCache;
+use ledger::{AccountLeg, Direction, EntryKind, JournalEntry, Ledger, LedgerAccount, Side};
+use locks::AccountLocks;
+use money::Money;
+use rate_limit::RateLimiter;
+use schedule::{NewSchedule, Recurrence, Run, RunOutcome, ScheduleStore};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use store::{AccountStore, MemoryStore, SqliteStore, StoreError, Transfer};
+use tls::Certificate;
 use uuid::Uuid;
+use validation::{MAX_AMOUNT, ValidJson, ValidQuery};
+use validator::{Validate, ValidationError};
+
+mod auth;
+mod currency;
+mod error;
+mod events;
+mod idempotency;
+mod ledger;
+mod locks;
+mod money;
+mod rate_limit;
+mod schedule;
+mod store;
+mod tls;
+mod treasury;
+mod validation;
 
 // --- Data Structures ---
 
@@ -10,225 +45,3017 @@ use uuid::Uuid;
 /// In the 'vulnerable' module, its fields are public, breaking encapsulation.
 /// In the 'secure' module, its fields are private, enforcing encapsulation.
 mod vulnerable_account {
-    use serde::Serialize;
+    use crate::events::EventSourced;
+    use crate::money::Money;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
     use uuid::Uuid;
 
-    #[derive(Debug, Clone, Serialize)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct BankAccount {
         pub account_number: Uuid,
-        pub balance: i32,
+        pub balance: Money,
     }
 
     impl BankAccount {
-        pub fn new(initial_balance: i32) -> Self {
+        pub fn new(initial_balance: Money) -> Self {
             Self {
                 account_number: Uuid::new_v4(),
                 balance: initial_balance,
             }
         }
     }
+
+    /// Changes to the vulnerable store, as written to its event log.
+    #[derive(Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum Event {
+        AccountOpened {
+            account: BankAccount,
+        },
+        /// The handler wrote a balance field directly. Recording the raw write
+        /// keeps replay faithful even for the half-finished transfers it allows.
+        BalanceSet {
+            account_number: Uuid,
+            balance: Money,
+        },
+        /// The secure twin of a just opened account could not be opened, so the
+        /// account is taken back out.
+        AccountDiscarded {
+            account_number: Uuid,
+        },
+    }
+
+    impl EventSourced for HashMap<Uuid, BankAccount> {
+        type Event = Event;
+
+        fn apply(&mut self, event: Event) -> Result<(), String> {
+            match event {
+                Event::AccountOpened { account } => {
+                    self.insert(account.account_number, account);
+                }
+                Event::BalanceSet {
+                    account_number,
+                    balance,
+                } => {
+                    let account = self
+                        .get_mut(&account_number)
+                        .ok_or_else(|| format!("unknown account {account_number}"))?;
+                    account.balance = balance;
+                }
+                Event::AccountDiscarded { account_number } => {
+                    self.remove(&account_number)
+                        .ok_or_else(|| format!("unknown account {account_number}"))?;
+                }
+            }
+            Ok(())
+        }
+    }
 }
 
 mod secure_account {
-    use serde::Serialize;
+    use crate::currency::Currency;
+    use crate::error::DomainError;
+    use crate::money::Money;
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
     use uuid::Uuid;
 
-    #[derive(Debug, Clone, Serialize)]
+    /// Where an account is in its lifecycle. Only active accounts can move money.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum AccountStatus {
+        #[default]
+        Active,
+        Frozen,
+        Closed,
+    }
+
+    /// Money reserved for a later capture. It stays in the balance but can no
+    /// longer be spent.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Hold {
+        pub id: Uuid,
+        /// What is still held, after any partial captures.
+        pub amount: Money,
+        pub placed_at: DateTime<Utc>,
+    }
+
+    // Deserialize is only used to restore snapshots; no request type embeds an account.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct BankAccount {
         pub account_number: Uuid, // account_number can be public
-        balance: i32,             // balance is private
+        balance: Money,           // balance is private
+        currency: Currency,       // the currency is fixed when the account is opened
+        #[serde(default)]
+        status: AccountStatus, // only changed through freeze/unfreeze/close
+        #[serde(default)]
+        overdraft_limit: Money, // how far below zero the balance may go
+        #[serde(default)]
+        holds: Vec<Hold>, // only changed through place_hold/capture_hold/release_hold
+        #[serde(default)]
+        version: u64, // bumped by every method that changes the account
+        #[serde(default)]
+        owner: Option<String>, // set when the account is opened
+        // Accounts recorded before this existed read as the Unix epoch.
+        #[serde(default)]
+        opened_at: DateTime<Utc>,
     }
 
     impl BankAccount {
-        pub fn new(initial_balance: i32) -> Self {
+        pub fn new(initial_balance: Money, currency: Currency) -> Self {
             Self {
                 account_number: Uuid::new_v4(),
                 balance: initial_balance,
+                currency,
+                status: AccountStatus::Active,
+                overdraft_limit: Money::ZERO,
+                holds: Vec::new(),
+                version: 0,
+                owner: None,
+                opened_at: Utc::now(),
             }
         }
 
+        pub fn with_owner(mut self, owner: String) -> Self {
+            self.owner = Some(owner);
+            self
+        }
+
+        /// Gives a newly opened account its overdraft limit. Unlike
+        /// `set_overdraft_limit`, this is part of opening, not a change.
+        pub fn with_overdraft_limit(mut self, limit: Money) -> Result<Self, DomainError> {
+            self.set_overdraft_limit(limit)?;
+            self.version = 0;
+            Ok(self)
+        }
+
         // Public getter for the balance to inspect it safely.
-        pub fn balance(&self) -> i32 {
+        pub fn balance(&self) -> Money {
             self.balance
         }
 
-        /// Securely deposits money.
-        pub fn deposit(&mut self, amount: i32) {
-            if amount > 0 {
-                self.balance += amount;
+        pub fn currency(&self) -> Currency {
+            self.currency
+        }
+
+        pub fn status(&self) -> AccountStatus {
+            self.status
+        }
+
+        pub fn overdraft_limit(&self) -> Money {
+            self.overdraft_limit
+        }
+
+        pub fn holds(&self) -> &[Hold] {
+            &self.holds
+        }
+
+        pub fn owner(&self) -> Option<&str> {
+            self.owner.as_deref()
+        }
+
+        pub fn opened_at(&self) -> DateTime<Utc> {
+            self.opened_at
+        }
+
+        /// Counts the changes made to the account, so clients can tell whether it
+        /// changed since they last read it.
+        pub fn version(&self) -> u64 {
+            self.version
+        }
+
+        /// Called by every method once it has changed the account.
+        fn touch(&mut self) {
+            self.version += 1;
+        }
+
+        /// The balance minus everything currently held; what withdrawals are checked against.
+        pub fn available_balance(&self) -> Result<Money, DomainError> {
+            self.holds.iter().try_fold(self.balance, |available, hold| {
+                available.checked_sub(hold.amount)
+            })
+        }
+
+        /// Sets how far below zero withdrawals may take the balance. A limit that
+        /// the balance is already beyond is refused rather than leaving the
+        /// account in breach of its own terms.
+        pub fn set_overdraft_limit(&mut self, limit: Money) -> Result<(), DomainError> {
+            if self.status == AccountStatus::Closed {
+                return Err(DomainError::AccountClosed);
+            }
+            if limit.is_negative() {
+                return Err(DomainError::NegativeOverdraftLimit);
+            }
+            if self.available_balance()?.checked_add(limit)?.is_negative() {
+                return Err(DomainError::BalanceBelowOverdraftLimit);
+            }
+            self.overdraft_limit = limit;
+            self.touch();
+            Ok(())
+        }
+
+        /// Stops all deposits and withdrawals until the account is unfrozen.
+        pub fn freeze(&mut self) -> Result<(), DomainError> {
+            match self.status {
+                AccountStatus::Active => {
+                    self.status = AccountStatus::Frozen;
+                    self.touch();
+                    Ok(())
+                }
+                AccountStatus::Frozen => Err(DomainError::AccountAlreadyFrozen),
+                AccountStatus::Closed => Err(DomainError::AccountClosed),
+            }
+        }
+
+        pub fn unfreeze(&mut self) -> Result<(), DomainError> {
+            match self.status {
+                AccountStatus::Frozen => {
+                    self.status = AccountStatus::Active;
+                    self.touch();
+                    Ok(())
+                }
+                AccountStatus::Active => Err(DomainError::AccountNotFrozen),
+                AccountStatus::Closed => Err(DomainError::AccountClosed),
+            }
+        }
+
+        /// Closes the account for good. Only an empty account can be closed,
+        /// so no money is ever stranded in it.
+        pub fn close(&mut self) -> Result<(), DomainError> {
+            if self.status == AccountStatus::Closed {
+                return Err(DomainError::AccountAlreadyClosed);
+            }
+            if self.balance != Money::ZERO {
+                return Err(DomainError::NonZeroBalance);
+            }
+            if !self.holds.is_empty() {
+                return Err(DomainError::OpenHolds);
+            }
+            self.status = AccountStatus::Closed;
+            self.touch();
+            Ok(())
+        }
+
+        /// Money can only move in or out of an active account.
+        fn ensure_active(&self) -> Result<(), DomainError> {
+            match self.status {
+                AccountStatus::Active => Ok(()),
+                AccountStatus::Frozen => Err(DomainError::AccountFrozen),
+                AccountStatus::Closed => Err(DomainError::AccountClosed),
+            }
+        }
+
+        /// Checks that `amount` can be taken from the available balance, going
+        /// below zero only as far as the overdraft limit allows.
+        fn ensure_spendable(&self, amount: Money) -> Result<(), DomainError> {
+            let remaining = self.available_balance()?.checked_sub(amount)?;
+            if remaining.is_negative() {
+                if self.overdraft_limit == Money::ZERO {
+                    return Err(DomainError::InsufficientFunds);
+                }
+                if remaining.checked_add(self.overdraft_limit)?.is_negative() {
+                    return Err(DomainError::OverdraftLimitExceeded);
+                }
+            }
+            Ok(())
+        }
+
+        /// Securely deposits money, rejecting amounts that would overflow the balance.
+        /// The amount must be in the account's own currency.
+        pub fn deposit(&mut self, amount: Money, currency: Currency) -> Result<(), DomainError> {
+            self.ensure_active()?;
+            if currency != self.currency {
+                return Err(DomainError::CurrencyMismatch);
             }
+            if !amount.is_positive() {
+                return Err(DomainError::InvalidAmount);
+            }
+            self.balance = self.balance.checked_add(amount)?;
+            self.touch();
+            Ok(())
         }
 
         /// Securely withdraws money, checking for sufficient funds.
         /// This is our validation check that was bypassed in the vulnerable example.
-        pub fn withdraw(&mut self, amount: i32) -> Result<(), &'static str> {
-            if amount <= 0 {
-                return Err("Withdrawal amount must be positive.");
+        /// Held money is not available, and the balance may go below zero only as
+        /// far as the overdraft limit allows.
+        /// The amount must be in the account's own currency.
+        pub fn withdraw(&mut self, amount: Money, currency: Currency) -> Result<(), DomainError> {
+            self.ensure_active()?;
+            if currency != self.currency {
+                return Err(DomainError::CurrencyMismatch);
+            }
+            if !amount.is_positive() {
+                return Err(DomainError::InvalidAmount);
+            }
+            self.ensure_spendable(amount)?;
+            self.balance = self.balance.checked_sub(amount)?;
+            self.touch();
+            Ok(())
+        }
+
+        /// Reserves `amount` for a later capture, under the same rules as a withdrawal.
+        pub fn place_hold(&mut self, amount: Money) -> Result<Uuid, DomainError> {
+            self.ensure_active()?;
+            if !amount.is_positive() {
+                return Err(DomainError::InvalidAmount);
+            }
+            self.ensure_spendable(amount)?;
+            let id = Uuid::new_v4();
+            self.holds.push(Hold {
+                id,
+                amount,
+                placed_at: Utc::now(),
+            });
+            self.touch();
+            Ok(id)
+        }
+
+        /// Takes `amount` out of a hold and out of the balance. Whatever is left of
+        /// the hold stays reserved until it is captured or released.
+        pub fn capture_hold(&mut self, hold_id: Uuid, amount: Money) -> Result<(), DomainError> {
+            self.ensure_active()?;
+            if !amount.is_positive() {
+                return Err(DomainError::InvalidAmount);
             }
-            if self.balance >= amount {
-                self.balance -= amount;
-                Ok(())
-            } else {
-                Err("Insufficient funds.")
+            let index = self.hold_index(hold_id)?;
+            let hold = &mut self.holds[index];
+            if amount > hold.amount {
+                return Err(DomainError::CaptureExceedsHold);
             }
+            // The money was already reserved, so no availability check is needed.
+            let balance = self.balance.checked_sub(amount)?;
+            hold.amount = hold.amount.checked_sub(amount)?;
+            if hold.amount == Money::ZERO {
+                self.holds.remove(index);
+            }
+            self.balance = balance;
+            self.touch();
+            Ok(())
+        }
+
+        /// Drops a hold, making what is left of it available again.
+        /// Allowed on a frozen account, since it moves no money.
+        pub fn release_hold(&mut self, hold_id: Uuid) -> Result<Hold, DomainError> {
+            let index = self.hold_index(hold_id)?;
+            let hold = self.holds.remove(index);
+            self.touch();
+            Ok(hold)
+        }
+
+        fn hold_index(&self, hold_id: Uuid) -> Result<usize, DomainError> {
+            self.holds
+                .iter()
+                .position(|hold| hold.id == hold_id)
+                .ok_or(DomainError::HoldNotFound)
+        }
+    }
+
+    /// The stored form of an account, for backends that keep each field in its own
+    /// column. An account rebuilt from a record skips every check in `deposit` and
+    /// `withdraw`, so only storage code should build one.
+    pub struct AccountRecord {
+        pub account_number: Uuid,
+        pub balance: Money,
+        pub currency: Currency,
+        pub status: AccountStatus,
+        pub overdraft_limit: Money,
+        pub holds: Vec<Hold>,
+        pub version: u64,
+        pub owner: Option<String>,
+        pub opened_at: DateTime<Utc>,
+    }
+
+    impl From<AccountRecord> for BankAccount {
+        fn from(record: AccountRecord) -> Self {
+            Self {
+                account_number: record.account_number,
+                balance: record.balance,
+                currency: record.currency,
+                status: record.status,
+                overdraft_limit: record.overdraft_limit,
+                holds: record.holds,
+                version: record.version,
+                owner: record.owner,
+                opened_at: record.opened_at,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const USD: Currency = Currency::DEFAULT;
+
+        fn money(minor: i64) -> Money {
+            Money::from_minor_units(minor)
+        }
+
+        fn account(balance: i64) -> BankAccount {
+            BankAccount::new(money(balance), USD)
+        }
+
+        #[test]
+        fn deposit_and_withdraw_move_the_balance() {
+            let mut account = account(100);
+            account.deposit(money(50), USD).unwrap();
+            account.withdraw(money(30), USD).unwrap();
+            assert_eq!(account.balance(), money(120));
+            assert_eq!(account.version(), 2);
+        }
+
+        #[test]
+        fn amounts_must_be_positive_and_in_the_account_currency() {
+            let mut account = account(100);
+            let eur = "EUR".parse().unwrap();
+            assert_eq!(
+                account.deposit(money(0), USD),
+                Err(DomainError::InvalidAmount)
+            );
+            assert_eq!(
+                account.withdraw(money(-5), USD),
+                Err(DomainError::InvalidAmount)
+            );
+            assert_eq!(
+                account.deposit(money(5), eur),
+                Err(DomainError::CurrencyMismatch)
+            );
+            assert_eq!(account.balance(), money(100));
+            assert_eq!(account.version(), 0);
+        }
+
+        #[test]
+        fn a_frozen_account_moves_no_money_until_unfrozen() {
+            let mut account = account(100);
+            account.freeze().unwrap();
+            assert_eq!(account.freeze(), Err(DomainError::AccountAlreadyFrozen));
+            assert_eq!(
+                account.withdraw(money(10), USD),
+                Err(DomainError::AccountFrozen)
+            );
+            assert_eq!(
+                account.deposit(money(10), USD),
+                Err(DomainError::AccountFrozen)
+            );
+            assert_eq!(
+                account.place_hold(money(10)),
+                Err(DomainError::AccountFrozen)
+            );
+            assert_eq!(account.balance(), money(100));
+
+            account.unfreeze().unwrap();
+            assert_eq!(account.unfreeze(), Err(DomainError::AccountNotFrozen));
+            account.withdraw(money(10), USD).unwrap();
+        }
+
+        #[test]
+        fn only_an_empty_account_without_holds_can_be_closed_and_then_for_good() {
+            let mut account = account(100);
+            assert_eq!(account.close(), Err(DomainError::NonZeroBalance));
+            let hold = account.place_hold(money(100)).unwrap();
+            account.capture_hold(hold, money(60)).unwrap();
+            assert_eq!(account.close(), Err(DomainError::NonZeroBalance));
+            account.capture_hold(hold, money(40)).unwrap();
+            assert!(account.holds().is_empty());
+
+            let mut held = BankAccount::new(money(0), USD)
+                .with_overdraft_limit(money(10))
+                .unwrap();
+            held.place_hold(money(5)).unwrap();
+            assert_eq!(held.close(), Err(DomainError::OpenHolds));
+
+            account.close().unwrap();
+            assert_eq!(account.status(), AccountStatus::Closed);
+            assert_eq!(account.close(), Err(DomainError::AccountAlreadyClosed));
+            assert_eq!(
+                account.deposit(money(1), USD),
+                Err(DomainError::AccountClosed)
+            );
+            assert_eq!(account.freeze(), Err(DomainError::AccountClosed));
+            assert_eq!(account.unfreeze(), Err(DomainError::AccountClosed));
+            assert_eq!(
+                account.set_overdraft_limit(money(10)),
+                Err(DomainError::AccountClosed)
+            );
+        }
+
+        #[test]
+        fn without_an_overdraft_the_balance_cannot_go_below_zero() {
+            let mut account = account(100);
+            assert_eq!(
+                account.withdraw(money(101), USD),
+                Err(DomainError::InsufficientFunds)
+            );
+            account.withdraw(money(100), USD).unwrap();
+            assert_eq!(account.balance(), Money::ZERO);
+        }
+
+        #[test]
+        fn withdrawals_may_use_the_overdraft_but_not_go_past_it() {
+            let mut account = account(100).with_overdraft_limit(money(50)).unwrap();
+            assert_eq!(account.version(), 0);
+            assert_eq!(
+                account.withdraw(money(151), USD),
+                Err(DomainError::OverdraftLimitExceeded)
+            );
+            account.withdraw(money(150), USD).unwrap();
+            assert_eq!(account.balance(), money(-50));
+            assert_eq!(
+                account.withdraw(money(1), USD),
+                Err(DomainError::OverdraftLimitExceeded)
+            );
+        }
+
+        #[test]
+        fn the_overdraft_limit_cannot_be_negative_or_below_the_current_overdraft() {
+            let mut account = account(0).with_overdraft_limit(money(50)).unwrap();
+            account.withdraw(money(40), USD).unwrap();
+            assert_eq!(
+                account.set_overdraft_limit(money(-1)),
+                Err(DomainError::NegativeOverdraftLimit)
+            );
+            assert_eq!(
+                account.set_overdraft_limit(money(39)),
+                Err(DomainError::BalanceBelowOverdraftLimit)
+            );
+            account.set_overdraft_limit(money(40)).unwrap();
+            assert_eq!(account.overdraft_limit(), money(40));
+        }
+
+        #[test]
+        fn a_hold_reduces_the_available_balance_but_not_the_balance() {
+            let mut account = account(100);
+            account.place_hold(money(70)).unwrap();
+            assert_eq!(account.balance(), money(100));
+            assert_eq!(account.available_balance(), Ok(money(30)));
+            assert_eq!(
+                account.withdraw(money(31), USD),
+                Err(DomainError::InsufficientFunds)
+            );
+            assert_eq!(
+                account.place_hold(money(31)),
+                Err(DomainError::InsufficientFunds)
+            );
+            account.withdraw(money(30), USD).unwrap();
+            assert_eq!(account.available_balance(), Ok(Money::ZERO));
+        }
+
+        #[test]
+        fn a_capture_takes_money_out_of_the_hold_and_the_balance() {
+            let mut account = account(100);
+            let hold = account.place_hold(money(70)).unwrap();
+            assert_eq!(
+                account.capture_hold(hold, money(71)),
+                Err(DomainError::CaptureExceedsHold)
+            );
+            account.capture_hold(hold, money(20)).unwrap();
+            assert_eq!(account.balance(), money(80));
+            assert_eq!(account.holds()[0].amount, money(50));
+            assert_eq!(account.available_balance(), Ok(money(30)));
+
+            account.capture_hold(hold, money(50)).unwrap();
+            assert!(account.holds().is_empty());
+            assert_eq!(
+                account.capture_hold(hold, money(1)),
+                Err(DomainError::HoldNotFound)
+            );
+        }
+
+        #[test]
+        fn releasing_a_hold_makes_it_available_again_even_when_frozen() {
+            let mut account = account(100);
+            let hold = account.place_hold(money(70)).unwrap();
+            account.freeze().unwrap();
+            assert_eq!(
+                account.capture_hold(hold, money(10)),
+                Err(DomainError::AccountFrozen)
+            );
+            assert_eq!(account.release_hold(hold).unwrap().amount, money(70));
+            assert_eq!(account.available_balance(), Ok(money(100)));
+            assert!(matches!(
+                account.release_hold(hold),
+                Err(DomainError::HoldNotFound)
+            ));
         }
     }
 }
 
 /// A struct to hold the shared application state.
-/// We use two separate HashMaps to clearly distinguish between the
+/// We use two separate stores to clearly distinguish between the
 /// vulnerable and secure data models in this demonstration.
+///
+/// The secure store is anything implementing `AccountStore`, which also keeps
+/// the double-entry ledger. The vulnerable store is a plain map, made durable
+/// by its own event log.
 struct AppState {
-    vulnerable_accounts: Mutex<HashMap<Uuid, vulnerable_account::BankAccount>>,
-    secure_accounts: Mutex<HashMap<Uuid, secure_account::BankAccount>>,
+    vulnerable_accounts: Mutex<VulnerableStore>,
+    /// Held for the whole of a vulnerable transfer; the store mutex above is only
+    /// held to write and apply each individual change, not while it syncs.
+    vulnerable_locks: AccountLocks,
+    secure_accounts: Box<dyn AccountStore>,
+    exchange_rates: RateTable,
+    /// Responses to account creations and secure transfers sent with an `Idempotency-Key`.
+    idempotency: IdempotencyCache,
+    /// Standing orders. The scheduler holds this lock while it executes due runs,
+    /// so a run never executes after its schedule was cancelled.
+    schedules: Mutex<ScheduleStore>,
 }
 
-#[derive(Deserialize)]
+/// The vulnerable accounts plus the log that makes them survive restarts.
+/// Handlers write an event before each change and call `checkpoint`, then wait
+/// for the event to be durable once they have let go of the store.
+struct VulnerableStore {
+    accounts: HashMap<Uuid, vulnerable_account::BankAccount>,
+    log: EventLog<HashMap<Uuid, vulnerable_account::BankAccount>>,
+}
+
+impl VulnerableStore {
+    /// Writes a snapshot if one is due. Only call this once every logged change
+    /// has been applied to `accounts`.
+    fn checkpoint(&mut self) {
+        if let Err(e) = self.log.snapshot_if_due(&self.accounts) {
+            eprintln!("⚠️  Failed to write snapshot: {e}");
+        }
+    }
+}
+
+/// The entity tag of an account as it is now: its version, as a strong tag.
+fn account_etag(account: &secure_account::BankAccount) -> ETag {
+    ETag(EntityTag::new_strong(account.version().to_string()))
+}
+
+/// Sends an account back to the client along with its `ETag`.
+fn account_response(account: &secure_account::BankAccount) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(account_etag(account))
+        .json(account)
+}
+
+/// Reads the `If-Match` precondition of a mutating request. `None` means the
+/// client sent none and does not mind intervening changes.
+fn if_match(req: &HttpRequest) -> Result<Option<IfMatch>, ApiError> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Ok(None);
+    }
+    req.get_header::<IfMatch>()
+        .map(Some)
+        .ok_or(ApiError::InvalidIfMatch)
+}
+
+/// Fails unless `account` still matches the client's `If-Match` precondition.
+/// Plans call this on the account they were handed, so the check and the change
+/// are one atomic step.
+fn check_if_match(
+    if_match: &Option<IfMatch>,
+    account: &secure_account::BankAccount,
+) -> Result<(), StoreError> {
+    let matches = match if_match {
+        None | Some(IfMatch::Any) => true,
+        Some(IfMatch::Items(tags)) => {
+            let current = account_etag(account).0;
+            tags.iter().any(|tag| tag.strong_eq(&current))
+        }
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(StoreError::VersionMismatch(account.account_number))
+    }
+}
+
+/// Refuses `account` unless `principal` may use it. Reported as if the account
+/// did not exist, so nobody can probe for other people's account numbers.
+fn check_owner(
+    principal: &Principal,
+    account: &secure_account::BankAccount,
+) -> Result<(), StoreError> {
+    if principal.may_access(account) {
+        Ok(())
+    } else {
+        Err(StoreError::AccountNotFound(account.account_number))
+    }
+}
+
+/// For a failed write to the vulnerable store's event log.
+fn record_failure(e: std::io::Error) -> ApiError {
+    ApiError::Internal(format!("Failed to record event: {e}"))
+}
+
+/// For a rule violation that only a bug could cause, e.g. an overflow while
+/// adding up the journal.
+fn internal(e: DomainError) -> ApiError {
+    ApiError::Internal(e.to_string())
+}
+
+/// Runs a handler's work on the blocking thread pool. Store operations take
+/// locks and wait for the disk or the database, which would otherwise stall an
+/// async worker along with every request queued on it.
+async fn blocking(
+    data: &web::Data<AppState>,
+    work: impl FnOnce(&AppState) -> Result<HttpResponse, ApiError> + Send + 'static,
+) -> Result<HttpResponse, ApiError> {
+    let data = data.clone();
+    // A response cannot leave the thread it was built on, so it travels in parts.
+    let (status, headers, body) = web::block(move || {
+        let (response, body) = work(&data)?.into_parts();
+        let body = body
+            .try_into_bytes()
+            .map_err(|_| ApiError::Internal("Response body is not held in memory.".to_owned()))?;
+        Ok::<_, ApiError>((response.status(), response.headers().clone(), body))
+    })
+    .await
+    .map_err(|e| ApiError::Internal(format!("Blocking task failed: {e}")))??;
+    let mut response = HttpResponse::with_body(status, body).map_into_boxed_body();
+    *response.headers_mut() = headers;
+    Ok(response)
+}
+
+/// Describes a request body for idempotency checks. Parsed bodies are compared,
+/// so formatting differences and spelled-out defaults still count as the same request.
+fn request_fingerprint(req: &impl Serialize) -> String {
+    serde_json::to_string(req).expect("request types always serialize")
+}
+
+// Request types also derive Serialize, for `request_fingerprint`.
+#[derive(Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
 struct CreateAccountRequest {
-    initial_balance: i32,
+    #[validate(range(min = Money::ZERO, max = MAX_AMOUNT))]
+    initial_balance: Money,
+    /// Currency of the secure account; the vulnerable model has no notion of currency.
+    #[serde(default = "default_currency")]
+    currency: Currency,
+    /// Overdraft limit of the secure account; zero means no overdraft. Only
+    /// admins may open an account with one.
+    #[serde(default)]
+    #[validate(range(min = Money::ZERO, max = MAX_AMOUNT))]
+    overdraft_limit: Money,
+    /// Who the secure account belongs to; defaults to the caller. Only admins
+    /// may open accounts for someone else.
+    #[serde(default)]
+    #[validate(length(min = 1, max = 64, message = "must be 1 to 64 characters long"))]
+    owner: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct OverdraftLimitRequest {
+    #[validate(range(min = Money::ZERO, max = MAX_AMOUNT))]
+    overdraft_limit: Money,
+}
+
+fn default_currency() -> Currency {
+    Currency::DEFAULT
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
 struct TransferRequest {
     from_account: Uuid,
     to_account: Uuid,
-    amount: i32,
+    /// Amount in the sender's currency.
+    #[validate(range(exclusive_min = Money::ZERO, max = MAX_AMOUNT))]
+    amount: Money,
+    /// Opt in to converting through the rate table when the two accounts use
+    /// different currencies. Without it, such transfers are rejected.
+    #[serde(default)]
+    convert: bool,
 }
 
-// --- API Handlers ---
+/// Returned by a successful secure transfer, recording the conversion that was applied.
+#[derive(Serialize)]
+struct TransferReceipt {
+    /// Identifies the transfer under `/secure/transfers/{id}`.
+    transfer_id: Uuid,
+    from_account: Uuid,
+    to_account: Uuid,
+    debited: Money,
+    debited_currency: Currency,
+    credited: Money,
+    credited_currency: Currency,
+    exchange_rate: ExchangeRate,
+    /// A transfer is recorded as a single journal entry, so this is the same id
+    /// as `transfer_id`.
+    journal_entry: Uuid,
+}
 
-/// Creates a new bank account in both vulnerable and secure stores for demonstration.
-async fn create_account(
-    data: web::Data<AppState>,
-    req: web::Json<CreateAccountRequest>,
-) -> impl Responder {
-    let mut vuln_accounts = data.vulnerable_accounts.lock().unwrap();
-    let mut sec_accounts = data.secure_accounts.lock().unwrap();
+/// A committed secure transfer or reversal, read back from its journal entry.
+#[derive(Serialize)]
+struct TransferRecord {
+    transfer_id: Uuid,
+    kind: EntryKind,
+    from_account: Uuid,
+    to_account: Uuid,
+    debited: Money,
+    debited_currency: Currency,
+    credited: Money,
+    credited_currency: Currency,
+    recorded_at: DateTime<Utc>,
+    /// For a reversal, the transfer it undid.
+    reverses: Option<Uuid>,
+    /// The reversal that undid this transfer, if it has been reversed.
+    reversed_by: Option<Uuid>,
+}
 
-    let vuln_account = vulnerable_account::BankAccount::new(req.initial_balance);
-    let sec_account = secure_account::BankAccount::new(req.initial_balance);
+impl TransferRecord {
+    /// `None` unless `entry` moved money from one customer account to another.
+    fn from_entry(entry: &JournalEntry, reversed_by: Option<Uuid>) -> Option<Self> {
+        if !matches!(entry.kind, EntryKind::Transfer | EntryKind::Reversal) {
+            return None;
+        }
+        let customer_posting = |side| {
+            entry
+                .postings
+                .iter()
+                .find_map(|posting| match posting.account {
+                    LedgerAccount::Customer(id) if posting.side == side => {
+                        Some((id, posting.amount, posting.currency))
+                    }
+                    _ => None,
+                })
+        };
+        let (from_account, debited, debited_currency) = customer_posting(Side::Debit)?;
+        let (to_account, credited, credited_currency) = customer_posting(Side::Credit)?;
+        Some(Self {
+            transfer_id: entry.id,
+            kind: entry.kind,
+            from_account,
+            to_account,
+            debited,
+            debited_currency,
+            credited,
+            credited_currency,
+            recorded_at: entry.recorded_at,
+            reverses: entry.reverses,
+            reversed_by,
+        })
+    }
+}
 
-    // To ensure both accounts have the same ID for easy comparison
-    let new_id = vuln_account.account_number;
-    let mut sec_account_mut = sec_account;
-    sec_account_mut.account_number = new_id;
+const MAX_BATCH_LEGS: u64 = 1000;
 
-    vuln_accounts.insert(new_id, vuln_account.clone());
-    sec_accounts.insert(new_id, sec_account_mut);
+/// Each leg is a transfer of its own, with the same fields as `TransferRequest`.
+#[derive(Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct BatchTransferRequest {
+    #[validate(length(min = 1, max = MAX_BATCH_LEGS), nested)]
+    legs: Vec<TransferRequest>,
+}
 
-    HttpResponse::Ok().json(&vuln_account)
+#[derive(Serialize)]
+struct BatchReceipt {
+    /// One receipt per leg, in request order.
+    legs: Vec<TransferReceipt>,
 }
 
-/// Retrieves an account's details (uses the secure model for display).
-async fn get_account(data: web::Data<AppState>, path: web::Path<Uuid>) -> impl Responder {
-    let account_id = path.into_inner();
-    let sec_accounts = data.secure_accounts.lock().unwrap();
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LegStatus {
+    /// The leg passed every check, but was not applied because another leg failed.
+    Valid,
+    Rejected,
+    /// The batch failed before this leg could be checked.
+    NotChecked,
+}
 
-    match sec_accounts.get(&account_id) {
-        Some(account) => HttpResponse::Ok().json(account),
-        None => HttpResponse::NotFound().body("Account not found"),
+#[derive(Serialize)]
+struct LegResult {
+    leg: usize,
+    status: LegStatus,
+    /// The `code` of the leg's error, as in a problem response.
+    code: Option<&'static str>,
+    error: Option<String>,
+}
+
+impl LegResult {
+    /// A leg with an error is rejected; one without gets `otherwise`.
+    fn new(leg: usize, error: Option<ApiError>, otherwise: LegStatus) -> Self {
+        match error {
+            Some(e) => Self {
+                leg,
+                status: LegStatus::Rejected,
+                code: Some(e.code()),
+                error: Some(e.to_string()),
+            },
+            None => Self {
+                leg,
+                status: otherwise,
+                code: None,
+                error: None,
+            },
+        }
     }
 }
 
-/// VULNERABLE transfer endpoint.
-async fn vulnerable_transfer(
-    data: web::Data<AppState>,
-    req: web::Json<TransferRequest>,
-) -> impl Responder {
-    let mut accounts = data.vulnerable_accounts.lock().unwrap();
+/// Added to the problem response of a rejected batch. Nothing in it was applied.
+#[derive(Serialize)]
+struct BatchFailure {
+    legs: Vec<LegResult>,
+}
 
-    // Direct access to fields, bypassing any logic or checks.
-    let from_balance = accounts.get_mut(&req.from_account).map(|a| &mut a.balance);
-    if let Some(balance) = from_balance {
-        *balance -= req.amount; // No check for sufficient funds!
-    } else {
-        return HttpResponse::NotFound().body("Sender account not found");
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+#[validate(
+    schema(function = check_schedule_window, skip_on_field_errors = false),
+    schema(function = check_recurrence, skip_on_field_errors = false)
+)]
+struct CreateScheduleRequest {
+    from_account: Uuid,
+    to_account: Uuid,
+    /// Amount in the sender's currency.
+    #[validate(range(exclusive_min = Money::ZERO, max = MAX_AMOUNT))]
+    amount: Money,
+    #[serde(default)]
+    convert: bool,
+    recurrence: Recurrence,
+    /// When the first run is due; now if omitted. Monthly schedules start on
+    /// their first day of the month at or after this time.
+    #[serde(default)]
+    start_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    end_at: Option<DateTime<Utc>>,
+}
+
+/// A schedule may not end before it starts. Whether it ends before its first
+/// run, which may come later, is up to the schedule store.
+fn check_schedule_window(req: &CreateScheduleRequest) -> Result<(), ValidationError> {
+    match (req.start_at, req.end_at) {
+        (Some(start_at), Some(end_at)) if end_at < start_at => Err(validation::struct_error(
+            "end_at",
+            "ends_before_start",
+            "must not be before start_at",
+        )),
+        _ => Ok(()),
     }
+}
 
-    let to_balance = accounts.get_mut(&req.to_account).map(|a| &mut a.balance);
-    if let Some(balance) = to_balance {
-        *balance += req.amount;
-    } else {
-        // NOTE: In a real scenario, this would require a transaction rollback.
-        // Here, the sender's money is just gone.
-        return HttpResponse::NotFound().body("Receiver account not found");
+/// The day of a monthly schedule must exist in at least some months.
+fn check_recurrence(req: &CreateScheduleRequest) -> Result<(), ValidationError> {
+    match req.recurrence {
+        Recurrence::Monthly { day } if !(1..=31).contains(&day) => Err(validation::struct_error(
+            "recurrence.day",
+            "range",
+            "must be between 1 and 31",
+        )),
+        _ => Ok(()),
     }
+}
 
-    HttpResponse::Ok().body("Vulnerable transfer processed.")
+#[derive(Deserialize)]
+struct ScheduleQuery {
+    /// Only schedules sending from or to this account.
+    account: Option<Uuid>,
 }
 
-/// SECURE transfer endpoint.
-async fn secure_transfer(
-    data: web::Data<AppState>,
-    req: web::Json<TransferRequest>,
-) -> impl Responder {
-    let mut accounts = data.secure_accounts.lock().unwrap();
+/// Query parameters for `GET /accounts/{id}/transactions`.
+#[derive(Deserialize, Validate)]
+#[validate(schema(function = check_transaction_window, skip_on_field_errors = false))]
+struct TransactionQuery {
+    /// The `next_cursor` of the previous page; omitted for the first page.
+    cursor: Option<Uuid>,
+    #[serde(default = "default_page_size")]
+    #[validate(range(min = 1, max = MAX_PAGE_SIZE))]
+    limit: usize,
+    /// Only include transactions recorded at or after this instant.
+    from: Option<DateTime<Utc>>,
+    /// Only include transactions recorded before this instant.
+    to: Option<DateTime<Utc>>,
+    direction: Option<Direction>,
+}
 
-    // Edge case: A transfer to the same account is invalid.
-    if req.from_account == req.to_account {
-        return HttpResponse::BadRequest().body("Sender and receiver accounts cannot be the same.");
+const MAX_PAGE_SIZE: usize = 200;
+
+fn default_page_size() -> usize {
+    50
+}
+
+fn check_transaction_window(query: &TransactionQuery) -> Result<(), ValidationError> {
+    match (query.from, query.to) {
+        (Some(from), Some(to)) if to < from => Err(validation::struct_error(
+            "to",
+            "ends_before_start",
+            "must not be before from",
+        )),
+        _ => Ok(()),
     }
+}
 
-    // Take ownership of the 'from' account by removing it from the map.
-    // Now the HashMap is no longer borrowed, and we can work with it again.
-    let mut from_account = match accounts.remove(&req.from_account) {
-        Some(account) => account,
-        None => return HttpResponse::NotFound().body("Sender account not found."),
-    };
+/// Query parameters for `GET /accounts`. Every filter is optional; the balance
+/// range is inclusive and in minor units of each account's own currency.
+#[derive(Deserialize, Validate)]
+#[validate(schema(function = check_balance_range, skip_on_field_errors = false))]
+struct AccountQuery {
+    /// The `next_cursor` of the previous page; omitted for the first page.
+    cursor: Option<Uuid>,
+    #[serde(default = "default_page_size")]
+    #[validate(range(min = 1, max = MAX_PAGE_SIZE))]
+    limit: usize,
+    #[serde(default)]
+    sort: AccountSort,
+    #[serde(default)]
+    order: SortOrder,
+    min_balance: Option<Money>,
+    max_balance: Option<Money>,
+    status: Option<secure_account::AccountStatus>,
+    owner: Option<String>,
+}
+
+fn check_balance_range(query: &AccountQuery) -> Result<(), ValidationError> {
+    match (query.min_balance, query.max_balance) {
+        (Some(min), Some(max)) if max < min => Err(validation::struct_error(
+            "max_balance",
+            "below_min_balance",
+            "must not be less than min_balance",
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AccountSort {
+    #[default]
+    OpenedAt,
+    Balance,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Serialize)]
+struct AccountPage {
+    accounts: Vec<secure_account::BankAccount>,
+    /// Pass this as `cursor` to fetch the next page; `None` on the last page.
+    next_cursor: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+struct TransactionPage {
+    transactions: Vec<AccountLeg>,
+    /// Pass this as `cursor` to fetch the next page; `None` on the last page.
+    next_cursor: Option<Uuid>,
+}
+
+/// A deposit or withdrawal.
+#[derive(Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct CashRequest {
+    #[validate(range(exclusive_min = Money::ZERO, max = MAX_AMOUNT))]
+    amount: Money,
+    /// Defaults to the account's currency; anything else is refused. The
+    /// vulnerable model ignores it.
+    #[serde(default)]
+    currency: Option<Currency>,
+}
+
+/// Money to mint into a treasury.
+#[derive(Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct MintRequest {
+    #[validate(range(exclusive_min = Money::ZERO, max = MAX_AMOUNT))]
+    amount: Money,
+    #[serde(default = "default_currency")]
+    currency: Currency,
+}
+
+#[derive(Serialize)]
+struct CashReceipt {
+    account_number: Uuid,
+    amount: Money,
+    currency: Currency,
+    /// The account's balance afterwards.
+    balance: Money,
+    journal_entry: Uuid,
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct PlaceHoldRequest {
+    /// Amount to reserve, in the account's currency.
+    #[validate(range(exclusive_min = Money::ZERO, max = MAX_AMOUNT))]
+    amount: Money,
+}
+
+#[derive(Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct CaptureHoldRequest {
+    /// How much of the hold to capture; the whole remaining hold if omitted.
+    #[serde(default)]
+    #[validate(range(exclusive_min = Money::ZERO, max = MAX_AMOUNT))]
+    amount: Option<Money>,
+}
+
+/// An account's holds, next to the two balances they make different.
+#[derive(Serialize)]
+struct HoldsView<'a> {
+    account_number: Uuid,
+    currency: Currency,
+    /// Everything the account holds, including reserved money.
+    ledger_balance: Money,
+    /// What can still be withdrawn, before any overdraft.
+    available_balance: Money,
+    holds: &'a [secure_account::Hold],
+}
+
+#[derive(Serialize)]
+struct CaptureReceipt {
+    account_number: Uuid,
+    hold_id: Uuid,
+    captured: Money,
+    currency: Currency,
+    /// What is still held after this capture; zero means the hold is gone.
+    remaining: Money,
+    journal_entry: Uuid,
+}
+
+/// One line of the ledger reconciliation report.
+#[derive(Serialize)]
+struct ReconciliationLine {
+    account_number: Uuid,
+    currency: Currency,
+    balance: Money,
+    ledger_balance: Money,
+    matches: bool,
+}
+
+#[derive(Serialize)]
+struct ReconciliationReport {
+    balanced: bool,
+    accounts: Vec<ReconciliationLine>,
+}
+
+// --- API Handlers ---
+
+/// Creates a new bank account in both vulnerable and secure stores for demonstration.
+/// A retry sent with the same `Idempotency-Key` gets the first response back
+/// instead of opening a second account.
+async fn create_account(
+    data: web::Data<AppState>,
+    principal: Principal,
+    http_req: HttpRequest,
+    req: ValidJson<CreateAccountRequest>,
+) -> Result<HttpResponse, ApiError> {
+    data.idempotency
+        .run(
+            &http_req,
+            "create_account",
+            request_fingerprint(&*req),
+            blocking(&data, move |data| open_account(data, &principal, &req)),
+        )
+        .await
+}
 
-    // Now that 'from_account' is separate, we can safely take 'to_account'.
-    let mut to_account = match accounts.remove(&req.to_account) {
-        Some(account) => account,
-        None => {
-            // IMPORTANT: If the 'to_account' doesn't exist, we must put the 'from_account'
-            // back into the map to cancel the transaction.
-            accounts.insert(from_account.account_number, from_account);
-            return HttpResponse::NotFound().body("Receiver account not found.");
+fn open_account(
+    data: &AppState,
+    principal: &Principal,
+    req: &CreateAccountRequest,
+) -> Result<HttpResponse, ApiError> {
+    let owner = match &req.owner {
+        Some(owner) if *owner != principal.subject && !principal.admin => {
+            return Err(ApiError::Forbidden);
         }
+        Some(owner) => owner.clone(),
+        None => principal.subject.clone(),
     };
-
-    // --- Perform the validated operation ---
-    // We now have full ownership of both accounts and can safely modify them.
-    if let Err(e) = from_account.withdraw(req.amount) {
-        // If the withdrawal fails, put both accounts back unchanged to abort the transaction.
-        accounts.insert(from_account.account_number, from_account);
-        accounts.insert(to_account.account_number, to_account);
-        return HttpResponse::BadRequest().body(e); // e.g., "Insufficient funds."
+    // Granting credit is the bank's decision, as with changing the limit later.
+    if req.overdraft_limit.is_positive() && !principal.admin {
+        return Err(ApiError::Forbidden);
     }
+    let vuln_account = vulnerable_account::BankAccount::new(req.initial_balance);
+    let sec_account = secure_account::BankAccount::new(Money::ZERO, req.currency)
+        .with_overdraft_limit(req.overdraft_limit)?
+        .with_owner(owner);
+
+    // To ensure both accounts have the same ID for easy comparison
+    let new_id = vuln_account.account_number;
+    let mut sec_account_mut = sec_account;
+    sec_account_mut.account_number = new_id;
 
-    // If withdrawal was successful, proceed with the deposit.
-    to_account.deposit(req.amount);
+    // The vulnerable account goes first, as it can be taken back out if the
+    // secure one is refused. The other way round, a failed vulnerable write
+    // would leave a funded secure account behind for a retry to open again.
+    let mut vulnerable = data.vulnerable_accounts.lock().unwrap();
+    let event = vulnerable_account::Event::AccountOpened {
+        account: vuln_account.clone(),
+    };
+    let pending = vulnerable.log.write(&event).map_err(record_failure)?;
+    vulnerable.accounts.insert(new_id, vuln_account.clone());
+    vulnerable.checkpoint();
+    drop(vulnerable);
+    pending.wait().map_err(record_failure)?;
 
-    // --- Commit the transaction ---
-    // The operation was successful, so put the modified accounts back into the map.
-    accounts.insert(from_account.account_number, from_account);
-    accounts.insert(to_account.account_number, to_account);
+    if let Err(e) = open_secure_account(data, sec_account_mut, req) {
+        discard_vulnerable_account(data, new_id);
+        return Err(e);
+    }
 
-    HttpResponse::Ok().body("Secure transfer successful.")
+    Ok(HttpResponse::Ok().json(&vuln_account))
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    // Initialize shared state
-    let app_state = web::Data::new(AppState {
-        vulnerable_accounts: Mutex::new(HashMap::new()),
-        secure_accounts: Mutex::new(HashMap::new()),
-    });
-
-    println!("🚀 Server starting at http://127.0.0.1:8080");
+/// Opens the secure twin of a new account, paying its opening balance out of
+/// the treasury.
+fn open_secure_account(
+    data: &AppState,
+    account: secure_account::BankAccount,
+    req: &CreateAccountRequest,
+) -> Result<(), ApiError> {
+    // Opening an account creates no money: the opening balance is transferred
+    // from the treasury, which only holds what an admin minted.
+    if req.initial_balance.is_positive() {
+        let treasury = treasury::account_id(req.currency);
+        let (amount, currency) = (req.initial_balance, req.currency);
+        data.secure_accounts
+            .create_funded(account, treasury, &mut |treasury, account| {
+                treasury.withdraw(amount, currency).map_err(|e| match e {
+                    DomainError::InsufficientFunds | DomainError::OverdraftLimitExceeded => {
+                        DomainError::TreasuryLacksFunds
+                    }
+                    e => e,
+                })?;
+                account.deposit(amount, currency)?;
+                let journal_entry = ledger::movement(
+                    LedgerAccount::Customer(treasury.account_number),
+                    LedgerAccount::Customer(account.account_number),
+                    amount,
+                    currency,
+                )
+                .and_then(|postings| JournalEntry::new(EntryKind::AccountOpening, postings))
+                .map_err(|e| StoreError::Backend(e.to_string()))?;
+                Ok(Transfer {
+                    debited: amount,
+                    credited: amount,
+                    journal_entry,
+                })
+            })
+            .map_err(|e| match e {
+                // No treasury means nothing was ever minted in this currency.
+                StoreError::AccountNotFound(id) if id == treasury => {
+                    ApiError::Rejected(DomainError::TreasuryLacksFunds)
+                }
+                e => e.into(),
+            })?;
+    } else {
+        let opening = JournalEntry::new(EntryKind::AccountOpening, Vec::new())?;
+        data.secure_accounts.create(account, opening)?;
+    }
+    Ok(())
+}
 
-    HttpServer::new(move || {
-        App::new()
+/// Takes a vulnerable account back out after its secure twin was refused. If
+/// even that cannot be recorded, the account stays, or comes back after a
+/// restart; it holds no real money.
+fn discard_vulnerable_account(data: &AppState, account_number: Uuid) {
+    let mut vulnerable = data.vulnerable_accounts.lock().unwrap();
+    let event = vulnerable_account::Event::AccountDiscarded { account_number };
+    let recorded = vulnerable.log.write(&event);
+    if recorded.is_ok() {
+        vulnerable.accounts.remove(&account_number);
+        vulnerable.checkpoint();
+    }
+    drop(vulnerable);
+    if let Err(e) = recorded.and_then(|pending| pending.wait()) {
+        eprintln!("⚠️  Failed to discard vulnerable account {account_number}: {e}");
+    }
+}
+
+/// Lists the secure accounts the caller may see (all of them for admins),
+/// filtered and sorted, one page at a time. Accounts with the same sort key are
+/// ordered by account number, so pages never overlap.
+async fn list_accounts(
+    data: web::Data<AppState>,
+    principal: Principal,
+    query: ValidQuery<AccountQuery>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        let mut accounts = data.secure_accounts.list()?;
+        accounts.retain(|account| principal.may_access(account));
+        let order = |a: &secure_account::BankAccount, b: &secure_account::BankAccount| {
+            let ordering = match query.sort {
+                AccountSort::OpenedAt => a.opened_at().cmp(&b.opened_at()),
+                AccountSort::Balance => a.balance().cmp(&b.balance()),
+            }
+            .then(a.account_number.cmp(&b.account_number));
+            match query.order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        };
+
+        // The cursor is the last account already returned. Resume after wherever it
+        // sorts now rather than after its old position, so a balance that changed
+        // between pages cannot make the listing jump.
+        let after = match query.cursor {
+            Some(cursor) => Some(
+                accounts
+                    .iter()
+                    .find(|account| account.account_number == cursor)
+                    .cloned()
+                    .ok_or(ApiError::UnknownCursor)?,
+            ),
+            None => None,
+        };
+        accounts.sort_by(order);
+
+        let mut matching = accounts.iter().filter(|account| {
+            after
+                .as_ref()
+                .is_none_or(|after| order(account, after).is_gt())
+                && query.min_balance.is_none_or(|min| account.balance() >= min)
+                && query.max_balance.is_none_or(|max| account.balance() <= max)
+                && query.status.is_none_or(|status| account.status() == status)
+                && query
+                    .owner
+                    .as_deref()
+                    .is_none_or(|owner| account.owner() == Some(owner))
+        });
+        let page: Vec<_> = matching.by_ref().take(query.limit).cloned().collect();
+        let next_cursor = match matching.next() {
+            Some(_) => page.last().map(|account| account.account_number),
+            None => None,
+        };
+
+        Ok(HttpResponse::Ok().json(AccountPage {
+            accounts: page,
+            next_cursor,
+        }))
+    })
+    .await
+}
+
+/// Retrieves an account's details (uses the secure model for display). Only
+/// the account's owner, or an admin, gets to see it.
+async fn get_account(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        let account = data.secure_accounts.get(path.into_inner())?;
+        check_owner(&principal, &account)?;
+        Ok(account_response(&account))
+    })
+    .await
+}
+
+/// VULNERABLE account lookup: returns any secure account to anyone who knows its
+/// number, without asking who owns it. An insecure direct object reference.
+async fn vulnerable_get_account(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        let account = data.secure_accounts.get(path.into_inner())?;
+        Ok(account_response(&account))
+    })
+    .await
+}
+
+/// Lists the journal entries that touched a secure account, oldest first.
+async fn get_account_transactions(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+    query: ValidQuery<TransactionQuery>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        let account_id = path.into_inner();
+        let account = data.secure_accounts.get(account_id)?;
+        check_owner(&principal, &account)?;
+        let currency = account.currency();
+        let ledger = Ledger::from_entries(data.secure_accounts.journal()?);
+        let history = ledger
+            .account_history(account_id, currency)
+            .map_err(internal)?;
+
+        // The cursor is the id of the last leg already returned, so resume right after it.
+        let start = match query.cursor {
+            Some(cursor) => {
+                history
+                    .iter()
+                    .position(|leg| leg.id == cursor)
+                    .ok_or(ApiError::UnknownCursor)?
+                    + 1
+            }
+            None => 0,
+        };
+
+        let mut matching = history[start..].iter().filter(|leg| {
+            query.from.is_none_or(|from| leg.recorded_at >= from)
+                && query.to.is_none_or(|to| leg.recorded_at < to)
+                && query
+                    .direction
+                    .is_none_or(|direction| leg.direction == direction)
+        });
+        let transactions: Vec<AccountLeg> = matching.by_ref().take(query.limit).cloned().collect();
+        let next_cursor = match matching.next() {
+            Some(_) => transactions.last().map(|leg| leg.id),
+            None => None,
+        };
+
+        Ok(HttpResponse::Ok().json(TransactionPage {
+            transactions,
+            next_cursor,
+        }))
+    })
+    .await
+}
+
+/// VULNERABLE transfer endpoint.
+async fn vulnerable_transfer(
+    data: web::Data<AppState>,
+    req: web::Json<TransferRequest>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        // Only transfers touching the same accounts wait for each other.
+        data.vulnerable_locks
+            .with_locked(&[req.from_account, req.to_account], || {
+                vulnerable_transfer_locked(&data.vulnerable_accounts, &req)
+            })
+    })
+    .await
+}
+
+fn vulnerable_transfer_locked(
+    shared: &Mutex<VulnerableStore>,
+    req: &TransferRequest,
+) -> Result<HttpResponse, ApiError> {
+    // Direct access to fields, bypassing any logic or checks.
+    // Each raw write is logged as it happens, so a restart reproduces the damage faithfully.
+    // The debit is durable before the credit is even looked at.
+    let mut guard = shared.lock().unwrap();
+    let store = &mut *guard;
+    let from_balance = store
+        .accounts
+        .get_mut(&req.from_account)
+        .map(|a| &mut a.balance);
+    let debited = if let Some(balance) = from_balance {
+        // No check for sufficient funds! Only arithmetic overflow is caught.
+        let new_balance = balance.checked_sub(req.amount)?;
+        let event = vulnerable_account::Event::BalanceSet {
+            account_number: req.from_account,
+            balance: new_balance,
+        };
+        let pending = store.log.write(&event).map_err(record_failure)?;
+        *balance = new_balance;
+        pending
+    } else {
+        return Err(ApiError::NotFound(Resource::Sender));
+    };
+    store.checkpoint();
+    // The store is free again here, between the two halves of the transfer.
+    drop(guard);
+    debited.wait().map_err(record_failure)?;
+
+    let mut guard = shared.lock().unwrap();
+    let store = &mut *guard;
+    let to_balance = store
+        .accounts
+        .get_mut(&req.to_account)
+        .map(|a| &mut a.balance);
+    let credited = if let Some(balance) = to_balance {
+        // As above, if this fails the sender has already been debited and nothing
+        // rolls it back.
+        let new_balance = balance.checked_add(req.amount)?;
+        let event = vulnerable_account::Event::BalanceSet {
+            account_number: req.to_account,
+            balance: new_balance,
+        };
+        let pending = store.log.write(&event).map_err(record_failure)?;
+        *balance = new_balance;
+        pending
+    } else {
+        // NOTE: In a real scenario, this would require a transaction rollback.
+        // Here, the sender's money is just gone.
+        return Err(ApiError::NotFound(Resource::Receiver));
+    };
+    store.checkpoint();
+    drop(guard);
+    credited.wait().map_err(record_failure)?;
+
+    Ok(HttpResponse::Ok().body("Vulnerable transfer processed."))
+}
+
+/// VULNERABLE deposit endpoint: adds to the public balance field directly.
+/// Nothing checks the amount, so a negative "deposit" quietly drains the account.
+async fn vulnerable_deposit(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    req: web::Json<CashRequest>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        vulnerable_adjust(data, path.into_inner(), req.amount, Money::checked_add)
+    })
+    .await
+}
+
+/// VULNERABLE withdrawal endpoint: subtracts from the public balance field directly,
+/// with no check for sufficient funds.
+async fn vulnerable_withdraw(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    req: web::Json<CashRequest>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        vulnerable_adjust(data, path.into_inner(), req.amount, Money::checked_sub)
+    })
+    .await
+}
+
+fn vulnerable_adjust(
+    data: &AppState,
+    account_id: Uuid,
+    amount: Money,
+    apply: fn(Money, Money) -> Result<Money, DomainError>,
+) -> Result<HttpResponse, ApiError> {
+    data.vulnerable_locks.with_locked(&[account_id], || {
+        let mut guard = data.vulnerable_accounts.lock().unwrap();
+        let store = &mut *guard;
+        let account = store
+            .accounts
+            .get_mut(&account_id)
+            .ok_or(ApiError::NotFound(Resource::Account))?;
+        // Only arithmetic overflow is caught.
+        let new_balance = apply(account.balance, amount)?;
+        let event = vulnerable_account::Event::BalanceSet {
+            account_number: account_id,
+            balance: new_balance,
+        };
+        let pending = store.log.write(&event).map_err(record_failure)?;
+        account.balance = new_balance;
+        let account = account.clone();
+        store.checkpoint();
+        drop(guard);
+        pending.wait().map_err(record_failure)?;
+        Ok(HttpResponse::Ok().json(account))
+    })
+}
+
+/// SECURE deposit endpoint: money paid in from outside the bank, through `deposit`.
+/// Only admins, acting as tellers who have taken the cash, may deposit.
+/// Honors `If-Match` and `Idempotency-Key` like a secure transfer.
+async fn secure_deposit(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+    http_req: HttpRequest,
+    req: ValidJson<CashRequest>,
+) -> Result<HttpResponse, ApiError> {
+    // Anyone else could credit themselves money that was never paid in.
+    if !principal.admin {
+        return Err(ApiError::Forbidden);
+    }
+    let if_match = if_match(&http_req)?;
+    let account_id = path.into_inner();
+    data.idempotency
+        .run(
+            &http_req,
+            "deposit",
+            request_fingerprint(&(account_id, &*req)),
+            blocking(&data, move |data| {
+                move_cash(
+                    data,
+                    &principal,
+                    &if_match,
+                    account_id,
+                    &req,
+                    EntryKind::Deposit,
+                )
+            }),
+        )
+        .await
+}
+
+/// SECURE withdrawal endpoint: money paid out of the bank, through `withdraw`, so
+/// holds and the overdraft limit are respected.
+async fn secure_withdraw(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+    http_req: HttpRequest,
+    req: ValidJson<CashRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    let account_id = path.into_inner();
+    data.idempotency
+        .run(
+            &http_req,
+            "withdraw",
+            request_fingerprint(&(account_id, &*req)),
+            blocking(&data, move |data| {
+                move_cash(
+                    data,
+                    &principal,
+                    &if_match,
+                    account_id,
+                    &req,
+                    EntryKind::Withdrawal,
+                )
+            }),
+        )
+        .await
+}
+
+/// Deposits into or withdraws from one secure account, posting the movement
+/// against the funding account since the money crosses the bank's boundary.
+fn move_cash(
+    data: &AppState,
+    principal: &Principal,
+    if_match: &Option<IfMatch>,
+    account_id: Uuid,
+    req: &CashRequest,
+    kind: EntryKind,
+) -> Result<HttpResponse, ApiError> {
+    let mut posted = None;
+    let account = data.secure_accounts.update(account_id, &mut |account| {
+        check_owner(principal, account)?;
+        check_if_match(if_match, account)?;
+        let currency = req.currency.unwrap_or(account.currency());
+        let customer = LedgerAccount::Customer(account_id);
+        let postings = match kind {
+            EntryKind::Deposit => {
+                account.deposit(req.amount, currency)?;
+                ledger::movement(LedgerAccount::Funding, customer, req.amount, currency)
+            }
+            _ => {
+                account.withdraw(req.amount, currency)?;
+                ledger::movement(customer, LedgerAccount::Funding, req.amount, currency)
+            }
+        };
+        let journal_entry = postings
+            .and_then(|postings| JournalEntry::new(kind, postings))
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+        posted = Some(journal_entry.id);
+        Ok(Some(journal_entry))
+    })?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(account_etag(&account))
+        .json(CashReceipt {
+            account_number: account_id,
+            amount: req.amount,
+            currency: account.currency(),
+            balance: account.balance(),
+            journal_entry: posted.expect("a committed update ran its plan"),
+        }))
+}
+
+/// SECURE transfer endpoint. Only the sender's owner may debit it.
+/// An `If-Match` header is checked against the sender, the account being debited.
+/// A retry sent with the same `Idempotency-Key` gets the first receipt back
+/// instead of moving the money again.
+async fn secure_transfer(
+    data: web::Data<AppState>,
+    principal: Principal,
+    http_req: HttpRequest,
+    req: ValidJson<TransferRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    data.idempotency
+        .run(
+            &http_req,
+            "secure_transfer",
+            request_fingerprint(&*req),
+            blocking(&data, move |data| {
+                perform_secure_transfer(data, &principal, &if_match, &req)
+            }),
+        )
+        .await
+}
+
+fn perform_secure_transfer(
+    data: &AppState,
+    principal: &Principal,
+    if_match: &Option<IfMatch>,
+    req: &TransferRequest,
+) -> Result<HttpResponse, ApiError> {
+    let receipt =
+        transfer_funds(data, Some(principal), req, if_match).map_err(|e| transfer_error(e, req))?;
+    Ok(HttpResponse::Ok().json(receipt))
+}
+
+/// Like converting `e` directly, but says which side of the transfer is missing.
+fn transfer_error(e: StoreError, req: &TransferRequest) -> ApiError {
+    match e {
+        StoreError::AccountNotFound(id) if id == req.from_account => {
+            ApiError::NotFound(Resource::Sender)
+        }
+        StoreError::AccountNotFound(_) => ApiError::NotFound(Resource::Receiver),
+        e => e.into(),
+    }
+}
+
+/// The validated path behind every secure transfer, whether it comes from a
+/// request or from a standing order. `principal` must own the sender; standing
+/// orders pass `None`, as their sender's owner was checked when they were set up.
+fn transfer_funds(
+    data: &AppState,
+    principal: Option<&Principal>,
+    req: &TransferRequest,
+    if_match: &Option<IfMatch>,
+) -> Result<TransferReceipt, StoreError> {
+    // Edge case: A transfer to the same account is invalid.
+    if req.from_account == req.to_account {
+        return Err(DomainError::SameAccount.into());
+    }
+
+    // The store hands us both accounts and commits our changes only if every step succeeds,
+    // so a failure anywhere below leaves both balances untouched.
+    let mut applied = None;
+    let result = data.secure_accounts.transfer(
+        req.from_account,
+        req.to_account,
+        &mut |from_account, to_account| {
+            if let Some(principal) = principal {
+                check_owner(principal, from_account)?;
+            }
+            check_if_match(if_match, from_account)?;
+            let (receipt, journal_entry) = move_funds(
+                &data.exchange_rates,
+                from_account,
+                to_account,
+                req.amount,
+                req.convert,
+            )?;
+            let transfer = Transfer {
+                debited: receipt.debited,
+                credited: receipt.credited,
+                journal_entry,
+            };
+            applied = Some(receipt);
+            Ok(transfer)
+        },
+    );
+
+    result.map(|_| applied.expect("a committed transfer ran its plan"))
+}
+
+/// Moves `amount` (in the sender's currency) from one secure account to another
+/// through their checked methods, and builds the journal entry recording it.
+/// Single and batch transfers both go through here, so they follow the same rules.
+fn move_funds(
+    rates: &RateTable,
+    from_account: &mut secure_account::BankAccount,
+    to_account: &mut secure_account::BankAccount,
+    amount: Money,
+    convert: bool,
+) -> Result<(TransferReceipt, JournalEntry), StoreError> {
+    // --- Work out what the receiver is credited ---
+    // Different currencies are only allowed when the caller explicitly asked for a conversion.
+    let (from_currency, to_currency) = (from_account.currency(), to_account.currency());
+    if from_currency != to_currency && !convert {
+        return Err(DomainError::CurrencyMismatch.into());
+    }
+    let rate = rates
+        .rate(from_currency, to_currency)
+        .ok_or(DomainError::NoExchangeRate)?;
+    let credited = rate.convert(amount)?;
+
+    // --- Perform the validated operation ---
+    from_account.withdraw(amount, from_currency)?; // e.g., "Insufficient funds."
+    // The converted amount may be unusable (e.g. rounds to zero) or overflow the receiver.
+    to_account.deposit(credited, to_currency)?;
+
+    // --- Record the movement in the ledger ---
+    let journal_entry = ledger::transfer_postings(
+        from_account.account_number,
+        amount,
+        from_currency,
+        to_account.account_number,
+        credited,
+        to_currency,
+    )
+    .and_then(|postings| JournalEntry::new(EntryKind::Transfer, postings))
+    .map_err(|e| StoreError::Backend(e.to_string()))?;
+
+    let receipt = TransferReceipt {
+        transfer_id: journal_entry.id,
+        from_account: from_account.account_number,
+        to_account: to_account.account_number,
+        debited: amount,
+        debited_currency: from_currency,
+        credited,
+        credited_currency: to_currency,
+        exchange_rate: rate,
+        journal_entry: journal_entry.id,
+    };
+    Ok((receipt, journal_entry))
+}
+
+/// Atomic batch of secure transfers: every leg is applied, or none is. The
+/// caller must own the sender of every leg. An `If-Match` header is refused
+/// rather than ignored, as one tag cannot stand for every account of a batch.
+async fn batch_transfer(
+    data: web::Data<AppState>,
+    principal: Principal,
+    http_req: HttpRequest,
+    req: ValidJson<BatchTransferRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if http_req.headers().contains_key(header::IF_MATCH) {
+        return Err(ApiError::IfMatchNotSupported);
+    }
+    data.idempotency
+        .run(
+            &http_req,
+            "batch_transfer",
+            request_fingerprint(&*req),
+            blocking(&data, move |data| {
+                perform_batch_transfer(data, &principal, &req)
+            }),
+        )
+        .await
+}
+
+fn perform_batch_transfer(
+    data: &AppState,
+    principal: &Principal,
+    req: &BatchTransferRequest,
+) -> Result<HttpResponse, ApiError> {
+    // Every account the batch touches, once each; legs refer to them by position.
+    let mut ids = Vec::new();
+    let mut positions = HashMap::new();
+    for leg in &req.legs {
+        for id in [leg.from_account, leg.to_account] {
+            positions.entry(id).or_insert_with(|| {
+                ids.push(id);
+                ids.len() - 1
+            });
+        }
+    }
+
+    let mut outcomes = Vec::new();
+    // A sender the caller does not own, reported as if it were missing.
+    let mut unowned = None;
+    let result = data.secure_accounts.update_many(&ids, &mut |accounts| {
+        outcomes.clear();
+        for leg in &req.legs {
+            let from_account = &accounts[positions[&leg.from_account]];
+            if !principal.may_access(from_account) {
+                unowned = Some(leg.from_account);
+                return Err(StoreError::AccountNotFound(leg.from_account));
+            }
+        }
+        let mut journal_entries = Vec::new();
+        for leg in &req.legs {
+            if leg.from_account == leg.to_account {
+                outcomes.push(Err(DomainError::SameAccount.into()));
+                continue;
+            }
+            let [from_account, to_account] = accounts
+                .get_disjoint_mut([positions[&leg.from_account], positions[&leg.to_account]])
+                .expect("the two accounts of a leg are distinct");
+            // A leg can fail after its withdrawal; undo it so the legs after this one
+            // are still checked against the right balances.
+            let before = (from_account.clone(), to_account.clone());
+            match move_funds(
+                &data.exchange_rates,
+                from_account,
+                to_account,
+                leg.amount,
+                leg.convert,
+            ) {
+                Ok((receipt, journal_entry)) => {
+                    journal_entries.push(journal_entry);
+                    outcomes.push(Ok(receipt));
+                }
+                Err(e @ StoreError::Backend(_)) => return Err(e),
+                Err(e) => {
+                    (*from_account, *to_account) = before;
+                    outcomes.push(Err(e));
+                }
+            }
+        }
+        // Any failed leg rolls the whole batch back; the per-leg results below
+        // say what went wrong where.
+        if let Some(e) = outcomes.iter().find_map(|outcome| match outcome {
+            Err(StoreError::Rejected(e)) => Some(*e),
+            _ => None,
+        }) {
+            return Err(StoreError::Rejected(e));
+        }
+        Ok(journal_entries)
+    });
+
+    match result {
+        Ok(_) => Ok(HttpResponse::Ok().json(BatchReceipt {
+            legs: outcomes.into_iter().flatten().collect(),
+        })),
+        Err(StoreError::AccountNotFound(missing)) => {
+            // Someone else's account is still fine to pay into.
+            let receiver_missing = unowned != Some(missing);
+            let legs = req
+                .legs
+                .iter()
+                .enumerate()
+                .map(|(leg, transfer)| {
+                    let error = if transfer.from_account == missing {
+                        Some(ApiError::NotFound(Resource::Sender))
+                    } else if receiver_missing && transfer.to_account == missing {
+                        Some(ApiError::NotFound(Resource::Receiver))
+                    } else {
+                        None
+                    };
+                    LegResult::new(leg, error, LegStatus::NotChecked)
+                })
+                .collect();
+            Ok(ApiError::NotFound(Resource::Account).problem_response(BatchFailure { legs }))
+        }
+        Err(StoreError::Rejected(_)) if outcomes.iter().any(Result::is_err) => {
+            let legs = outcomes
+                .into_iter()
+                .enumerate()
+                .map(|(leg, outcome)| {
+                    LegResult::new(leg, outcome.err().map(ApiError::from), LegStatus::Valid)
+                })
+                .collect();
+            Ok(ApiError::BatchRejected.problem_response(BatchFailure { legs }))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Looks up a secure transfer, or a reversal, by its id. Only the owners of
+/// the two accounts involved can see it.
+async fn get_transfer(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        Ok(HttpResponse::Ok().json(find_transfer(data, &principal, path.into_inner())?))
+    })
+    .await
+}
+
+fn find_transfer(
+    data: &AppState,
+    principal: &Principal,
+    id: Uuid,
+) -> Result<TransferRecord, ApiError> {
+    let not_found = || ApiError::NotFound(Resource::Transfer);
+    let Some(entry) = data.secure_accounts.journal_entry(id)? else {
+        return Err(not_found());
+    };
+    let reversed_by = data.secure_accounts.reversal_of(id)?;
+    let record = TransferRecord::from_entry(&entry, reversed_by).ok_or_else(not_found)?;
+    for id in [record.from_account, record.to_account] {
+        if principal.may_access(&data.secure_accounts.get(id)?) {
+            return Ok(record);
+        }
+    }
+    Err(not_found())
+}
+
+/// Undoes a secure transfer by moving the same amounts back, each in its own
+/// account's currency, so a conversion is undone at the rate it was made at.
+/// The receiver must still have the money available, without dipping into an
+/// overdraft, and a transfer can only be reversed once.
+/// Only the receiver's owner can give the money back; the sender cannot claw
+/// it back on their own.
+/// An `If-Match` header is checked against the receiver, the account being debited.
+async fn reverse_transfer(
+    data: web::Data<AppState>,
+    principal: Principal,
+    http_req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    let id = path.into_inner();
+    data.idempotency
+        .run(
+            &http_req,
+            "reverse_transfer",
+            request_fingerprint(&id),
+            blocking(&data, move |data| {
+                perform_reversal(data, &principal, &if_match, id)
+            }),
+        )
+        .await
+}
+
+fn perform_reversal(
+    data: &AppState,
+    principal: &Principal,
+    if_match: &Option<IfMatch>,
+    id: Uuid,
+) -> Result<HttpResponse, ApiError> {
+    let original = find_transfer(data, principal, id)?;
+    // Owners never change, so this cannot go stale before the transfer below.
+    if !principal.may_access(&data.secure_accounts.get(original.to_account)?) {
+        return Err(ApiError::Forbidden);
+    }
+    if original.kind == EntryKind::Reversal {
+        return Err(DomainError::ReversalNotReversible.into());
+    }
+    if original.reversed_by.is_some() {
+        return Err(DomainError::TransferAlreadyReversed.into());
+    }
+
+    // Checked again by the store, atomically with the commit, in case another
+    // reversal of the same transfer got in since the lookup above.
+    let result = data.secure_accounts.transfer(
+        original.to_account,
+        original.from_account,
+        &mut |receiver, sender| {
+            check_if_match(if_match, receiver)?;
+            if receiver.available_balance()? < original.credited {
+                return Err(DomainError::ReceiverLacksFunds.into());
+            }
+            receiver.withdraw(original.credited, original.credited_currency)?;
+            sender.deposit(original.debited, original.debited_currency)?;
+
+            let mut journal_entry = ledger::transfer_postings(
+                receiver.account_number,
+                original.credited,
+                original.credited_currency,
+                sender.account_number,
+                original.debited,
+                original.debited_currency,
+            )
+            .and_then(|postings| JournalEntry::new(EntryKind::Reversal, postings))
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+            journal_entry.reverses = Some(id);
+            Ok(Transfer {
+                debited: original.credited,
+                credited: original.debited,
+                journal_entry,
+            })
+        },
+    );
+
+    let reversal = TransferRecord::from_entry(&result?.journal_entry, None)
+        .expect("a reversal moves money between two customer accounts");
+    Ok(HttpResponse::Ok().json(reversal))
+}
+
+/// Shows an account's holds and its available balance.
+async fn get_holds(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        let account = data.secure_accounts.get(path.into_inner())?;
+        check_owner(&principal, &account)?;
+        let available_balance = account.available_balance().map_err(internal)?;
+        Ok(HttpResponse::Ok()
+            .insert_header(account_etag(&account))
+            .json(HoldsView {
+                account_number: account.account_number,
+                currency: account.currency(),
+                ledger_balance: account.balance(),
+                available_balance,
+                holds: account.holds(),
+            }))
+    })
+    .await
+}
+
+/// Reserves money on an account without moving it.
+async fn place_hold(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+    http_req: HttpRequest,
+    req: ValidJson<PlaceHoldRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    blocking(&data, move |data| {
+        let mut placed = None;
+        let account = data
+            .secure_accounts
+            .update(path.into_inner(), &mut |account| {
+                check_owner(&principal, account)?;
+                check_if_match(&if_match, account)?;
+                placed = Some(account.place_hold(req.amount)?);
+                Ok(None)
+            })?;
+        let hold_id = placed.expect("a committed update ran its plan");
+        let hold = account.holds().iter().find(|hold| hold.id == hold_id);
+        Ok(HttpResponse::Ok().json(hold))
+    })
+    .await
+}
+
+/// Settles some or all of a hold: the money leaves the account for good.
+async fn capture_hold(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<(Uuid, Uuid)>,
+    http_req: HttpRequest,
+    req: ValidJson<CaptureHoldRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    blocking(&data, move |data| {
+        let (account_id, hold_id) = path.into_inner();
+        let mut captured = None;
+        let account = data.secure_accounts.update(account_id, &mut |account| {
+            check_owner(&principal, account)?;
+            check_if_match(&if_match, account)?;
+            let amount = match req.amount {
+                Some(amount) => amount,
+                None => account
+                    .holds()
+                    .iter()
+                    .find(|hold| hold.id == hold_id)
+                    .map(|hold| hold.amount)
+                    .ok_or(DomainError::HoldNotFound)?,
+            };
+            account.capture_hold(hold_id, amount)?;
+
+            // Captured money leaves the bank, so it is settled outside the customer accounts.
+            let journal_entry = ledger::movement(
+                LedgerAccount::Customer(account_id),
+                LedgerAccount::Settlement,
+                amount,
+                account.currency(),
+            )
+            .and_then(|postings| JournalEntry::new(EntryKind::HoldCapture, postings))
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+
+            captured = Some((amount, journal_entry.id));
+            Ok(Some(journal_entry))
+        })?;
+        let (amount, journal_entry) = captured.expect("a committed update ran its plan");
+        let remaining = account
+            .holds()
+            .iter()
+            .find(|hold| hold.id == hold_id)
+            .map_or(Money::ZERO, |hold| hold.amount);
+
+        Ok(HttpResponse::Ok().json(CaptureReceipt {
+            account_number: account_id,
+            hold_id,
+            captured: amount,
+            currency: account.currency(),
+            remaining,
+            journal_entry,
+        }))
+    })
+    .await
+}
+
+/// Drops a hold without capturing it, returning the released hold.
+async fn release_hold(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<(Uuid, Uuid)>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    blocking(&data, move |data| {
+        let (account_id, hold_id) = path.into_inner();
+        let mut released = None;
+        data.secure_accounts.update(account_id, &mut |account| {
+            check_owner(&principal, account)?;
+            check_if_match(&if_match, account)?;
+            released = Some(account.release_hold(hold_id)?);
+            Ok(None)
+        })?;
+        Ok(HttpResponse::Ok().json(released))
+    })
+    .await
+}
+
+// --- Standing Orders ---
+
+/// Sets up a standing order. Only the sender's owner may, since its runs are
+/// not checked again.
+async fn create_schedule(
+    data: web::Data<AppState>,
+    principal: Principal,
+    req: ValidJson<CreateScheduleRequest>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        // Catch typos in account ids now rather than at the first run.
+        let transfer = TransferRequest {
+            from_account: req.from_account,
+            to_account: req.to_account,
+            amount: req.amount,
+            convert: req.convert,
+        };
+        data.secure_accounts
+            .get(req.from_account)
+            .and_then(|sender| check_owner(&principal, &sender))
+            .map_err(|e| transfer_error(e, &transfer))?;
+        data.secure_accounts
+            .get(req.to_account)
+            .map_err(|e| transfer_error(e, &transfer))?;
+        let new = NewSchedule {
+            from_account: req.from_account,
+            to_account: req.to_account,
+            amount: req.amount,
+            convert: req.convert,
+            recurrence: req.recurrence,
+            start_at: req.start_at.unwrap_or_else(Utc::now),
+            end_at: req.end_at,
+        };
+        let schedule = data.schedules.lock().unwrap().create(new)?;
+        Ok(HttpResponse::Ok().json(schedule))
+    })
+    .await
+}
+
+/// Whether `principal` may see and cancel `schedule`: whoever owns its sender.
+fn owns_schedule(
+    data: &AppState,
+    principal: &Principal,
+    schedule: &schedule::Schedule,
+) -> Result<bool, ApiError> {
+    let sender = data.secure_accounts.get(schedule.from_account)?;
+    Ok(principal.may_access(&sender))
+}
+
+/// Lists the caller's standing orders, oldest first, optionally only those
+/// involving one account.
+async fn list_schedules(
+    data: web::Data<AppState>,
+    principal: Principal,
+    query: web::Query<ScheduleQuery>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        let schedules = data.schedules.lock().unwrap();
+        let mut matching = Vec::new();
+        for schedule in schedules.list() {
+            let involved = query.account.is_none_or(|account| {
+                schedule.from_account == account || schedule.to_account == account
+            });
+            if involved && owns_schedule(data, &principal, schedule)? {
+                matching.push(schedule);
+            }
+        }
+        Ok(HttpResponse::Ok().json(matching))
+    })
+    .await
+}
+
+async fn get_schedule(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        let schedules = data.schedules.lock().unwrap();
+        match schedules.get(path.into_inner()) {
+            Some(schedule) if owns_schedule(data, &principal, schedule)? => {
+                Ok(HttpResponse::Ok().json(schedule))
+            }
+            _ => Err(ApiError::NotFound(Resource::Schedule)),
+        }
+    })
+    .await
+}
+
+async fn cancel_schedule(
+    data: web::Data<AppState>,
+    principal: Principal,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        let id = path.into_inner();
+        let mut schedules = data.schedules.lock().unwrap();
+        match schedules.get(id) {
+            Some(schedule) if owns_schedule(data, &principal, schedule)? => {}
+            _ => return Err(ApiError::NotFound(Resource::Schedule)),
+        }
+        let schedule = schedules.cancel(id)?;
+        Ok(HttpResponse::Ok().json(schedule))
+    })
+    .await
+}
+
+/// Executes every standing order that is due, through the same path as `secure_transfer`.
+/// A refused transfer (e.g. insufficient funds) is recorded on the schedule, which
+/// then carries on with its next run.
+fn run_due_schedules(data: &AppState) {
+    let now = Utc::now();
+    let mut schedules = data.schedules.lock().unwrap();
+    for schedule in schedules.due(now) {
+        let scheduled_for = schedule.next_run_at.expect("due schedules have a next run");
+        // Take the run off the schedule first: if we crash during the transfer,
+        // the run is skipped rather than executed twice.
+        if let Err(e) = schedules.advance(schedule.id, now) {
+            eprintln!("⚠️  Failed to advance schedule {}: {e:?}", schedule.id);
+            continue;
+        }
+
+        let transfer = TransferRequest {
+            from_account: schedule.from_account,
+            to_account: schedule.to_account,
+            amount: schedule.amount,
+            convert: schedule.convert,
+        };
+        let outcome = match transfer_funds(data, None, &transfer, &None) {
+            Ok(receipt) => RunOutcome::Succeeded {
+                journal_entry: receipt.journal_entry,
+            },
+            Err(StoreError::Backend(reason)) => {
+                eprintln!("⚠️  Schedule {} failed to run: {reason}", schedule.id);
+                RunOutcome::Failed {
+                    reason: ApiError::Internal(reason).to_string(),
+                }
+            }
+            Err(e) => RunOutcome::Failed {
+                reason: e.to_string(),
+            },
+        };
+        let run = Run {
+            scheduled_for,
+            executed_at: Utc::now(),
+            outcome,
+        };
+        if let Err(e) = schedules.record_run(schedule.id, run) {
+            eprintln!(
+                "⚠️  Failed to record run of schedule {}: {e:?}",
+                schedule.id
+            );
+        }
+    }
+}
+
+// --- Admin Handlers ---
+
+/// Applies a lifecycle transition to a secure account and returns the updated account.
+/// The transition itself is a `BankAccount` method, so the rules live in one place.
+fn change_status(
+    data: &AppState,
+    account_id: Uuid,
+    if_match: &Option<IfMatch>,
+    transition: fn(&mut secure_account::BankAccount) -> Result<(), DomainError>,
+) -> Result<HttpResponse, ApiError> {
+    let account = data.secure_accounts.update(account_id, &mut |account| {
+        check_if_match(if_match, account)?;
+        transition(account)?;
+        Ok(None)
+    })?;
+    Ok(account_response(&account))
+}
+
+async fn freeze_account(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    blocking(&data, move |data| {
+        change_status(
+            data,
+            path.into_inner(),
+            &if_match,
+            secure_account::BankAccount::freeze,
+        )
+    })
+    .await
+}
+
+async fn unfreeze_account(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    blocking(&data, move |data| {
+        change_status(
+            data,
+            path.into_inner(),
+            &if_match,
+            secure_account::BankAccount::unfreeze,
+        )
+    })
+    .await
+}
+
+async fn close_account(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    http_req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    blocking(&data, move |data| {
+        change_status(
+            data,
+            path.into_inner(),
+            &if_match,
+            secure_account::BankAccount::close,
+        )
+    })
+    .await
+}
+
+async fn set_overdraft_limit(
+    data: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    http_req: HttpRequest,
+    req: ValidJson<OverdraftLimitRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let if_match = if_match(&http_req)?;
+    blocking(&data, move |data| {
+        let account = data
+            .secure_accounts
+            .update(path.into_inner(), &mut |account| {
+                check_if_match(&if_match, account)?;
+                account.set_overdraft_limit(req.overdraft_limit)?;
+                Ok(None)
+            })?;
+        Ok(account_response(&account))
+    })
+    .await
+}
+
+/// Mints new money into the treasury of the requested currency, opening the
+/// treasury on first use.
+async fn mint(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    req: ValidJson<MintRequest>,
+) -> Result<HttpResponse, ApiError> {
+    data.idempotency
+        .run(
+            &http_req,
+            "mint",
+            request_fingerprint(&*req),
+            blocking(&data, move |data| issue(data, &req)),
+        )
+        .await
+}
+
+fn issue(data: &AppState, req: &MintRequest) -> Result<HttpResponse, ApiError> {
+    let treasury = treasury::open(data.secure_accounts.as_ref(), req.currency)?;
+    let mut posted = None;
+    let account = data.secure_accounts.update(treasury, &mut |account| {
+        account.deposit(req.amount, req.currency)?;
+        let journal_entry = ledger::movement(
+            LedgerAccount::Funding,
+            LedgerAccount::Customer(treasury),
+            req.amount,
+            req.currency,
+        )
+        .and_then(|postings| JournalEntry::new(EntryKind::Issuance, postings))
+        .map_err(|e| StoreError::Backend(e.to_string()))?;
+        posted = Some(journal_entry.id);
+        Ok(Some(journal_entry))
+    })?;
+
+    Ok(HttpResponse::Ok().json(CashReceipt {
+        account_number: treasury,
+        amount: req.amount,
+        currency: req.currency,
+        balance: account.balance(),
+        journal_entry: posted.expect("a committed update ran its plan"),
+    }))
+}
+
+/// Reports the money supply per currency: what was minted, deposited and paid
+/// out, what the treasury still holds and what is in circulation.
+async fn treasury_supply(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        let ledger = Ledger::from_entries(data.secure_accounts.journal()?);
+        let accounts = data.secure_accounts.list()?;
+        let supply = treasury::supply(&accounts, &ledger).map_err(internal)?;
+        Ok(HttpResponse::Ok().json(supply))
+    })
+    .await
+}
+
+/// Lists every journal entry posted so far, oldest first.
+async fn ledger_entries(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        Ok(HttpResponse::Ok().json(data.secure_accounts.journal()?))
+    })
+    .await
+}
+
+/// Compares each secure account's stored balance with the balance derived from the journal.
+async fn ledger_reconciliation(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    blocking(&data, move |data| {
+        // Read the journal first: anything committed after it shows up as a mismatch
+        // rather than being hidden.
+        let ledger = Ledger::from_entries(data.secure_accounts.journal()?);
+        let accounts = data.secure_accounts.list()?;
+
+        let mut lines = Vec::with_capacity(accounts.len());
+        for account in &accounts {
+            let ledger_balance = ledger
+                .balance(
+                    LedgerAccount::Customer(account.account_number),
+                    account.currency(),
+                )
+                .map_err(internal)?;
+            lines.push(ReconciliationLine {
+                account_number: account.account_number,
+                currency: account.currency(),
+                balance: account.balance(),
+                ledger_balance,
+                matches: account.balance() == ledger_balance,
+            });
+        }
+
+        Ok(HttpResponse::Ok().json(ReconciliationReport {
+            balanced: lines.iter().all(|line| line.matches),
+            accounts: lines,
+        }))
+    })
+    .await
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    // Rebuild both account stores from their event logs.
+    let data_dir = PathBuf::from(std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_owned()));
+    let snapshot_every = match std::env::var("SNAPSHOT_EVERY") {
+        Ok(value) => value.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "SNAPSHOT_EVERY must be a non-negative integer",
+            )
+        })?,
+        Err(_) => 1000,
+    };
+    let (vulnerable_log, vulnerable_accounts) =
+        EventLog::open(data_dir.join("vulnerable"), snapshot_every)?;
+    // The secure store is pluggable: the event-sourced in-memory store by default,
+    // or an SQLite database with real transactions.
+    let secure_accounts: Box<dyn AccountStore> = match std::env::var("SECURE_STORE").as_deref() {
+        Ok("sqlite") => {
+            let path = std::env::var("SQLITE_PATH")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| data_dir.join("secure.db"));
+            std::fs::create_dir_all(&data_dir)?;
+            let store = SqliteStore::open(&path).map_err(std::io::Error::other)?;
+            println!("🗄️  Secure accounts are stored in {}", path.display());
+            Box::new(store)
+        }
+        Ok("memory") | Err(_) => {
+            Box::new(MemoryStore::open(data_dir.join("secure"), snapshot_every)?)
+        }
+        Ok(other) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("SECURE_STORE must be `memory` or `sqlite`, not `{other}`"),
+            ));
+        }
+    };
+
+    // Load exchange rates for cross-currency secure transfers.
+    // A missing file just means only same-currency transfers are possible.
+    let rates_path =
+        std::env::var("EXCHANGE_RATES_PATH").unwrap_or_else(|_| "rates.txt".to_owned());
+    let exchange_rates = match RateTable::load(&rates_path) {
+        Ok(rates) => rates,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!(
+                "⚠️  No exchange rate file at {rates_path}; cross-currency transfers are disabled"
+            );
+            RateTable::default()
+        }
+        Err(e) => return Err(e),
+    };
+
+    // How long a response to a request with an Idempotency-Key is kept for retries.
+    let idempotency_window = match std::env::var("IDEMPOTENCY_WINDOW_SECS") {
+        Ok(value) => value.parse().map(Duration::from_secs).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "IDEMPOTENCY_WINDOW_SECS must be a non-negative integer",
+            )
+        })?,
+        Err(_) => Duration::from_secs(24 * 60 * 60),
+    };
+
+    // Credentials for the protected routes. With none configured, only the open
+    // routes can be used.
+    let mut authenticator = Authenticator::default();
+    let api_keys_path =
+        std::env::var("API_KEYS_PATH").unwrap_or_else(|_| "api_keys.txt".to_owned());
+    match authenticator.load_api_keys(&api_keys_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("⚠️  No API key file at {api_keys_path}; API keys are disabled");
+        }
+        Err(e) => return Err(e),
+    }
+    if let Ok(secret) = std::env::var("JWT_HS256_SECRET") {
+        authenticator.accept_hs256(secret.as_bytes());
+    }
+    if let Ok(path) = std::env::var("JWT_EDDSA_PUBLIC_KEY_PATH") {
+        authenticator.accept_eddsa(&std::fs::read(path)?)?;
+    }
+    if let Ok(issuer) = std::env::var("JWT_ISSUER") {
+        authenticator.require_issuer(issuer);
+    }
+    if let Ok(audience) = std::env::var("JWT_AUDIENCE") {
+        authenticator.require_audience(audience);
+    }
+    if authenticator.api_key_count() == 0 && !authenticator.accepts_jwts() {
+        println!("⚠️  No credentials are configured; only the open routes can be used");
+    }
+    let authenticator = web::Data::new(authenticator);
+
+    // Per-client limits on how often each route may be called. A missing file
+    // means no limits at all.
+    let rate_limits_path =
+        std::env::var("RATE_LIMITS_PATH").unwrap_or_else(|_| "rate_limits.txt".to_owned());
+    let rate_limiter = match RateLimiter::load(&rate_limits_path) {
+        Ok(limiter) => limiter,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("⚠️  No rate limit file at {rate_limits_path}; requests are not limited");
+            RateLimiter::default()
+        }
+        Err(e) => return Err(e),
+    };
+    let rate_limiter = web::Data::new(rate_limiter);
+
+    // The vulnerable routes stay open by default, so the lesson works without credentials.
+    let vulnerable_requires_auth = match std::env::var("VULNERABLE_REQUIRES_AUTH").as_deref() {
+        Ok("true") => true,
+        Ok("false") | Err(_) => false,
+        Ok(other) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("VULNERABLE_REQUIRES_AUTH must be `true` or `false`, not `{other}`"),
+            ));
+        }
+    };
+
+    // Initialize shared state
+    let app_state = web::Data::new(AppState {
+        vulnerable_accounts: Mutex::new(VulnerableStore {
+            accounts: vulnerable_accounts,
+            log: vulnerable_log,
+        }),
+        vulnerable_locks: AccountLocks::default(),
+        secure_accounts,
+        exchange_rates,
+        idempotency: IdempotencyCache::open(
+            data_dir.join("idempotency"),
+            idempotency_window,
+            snapshot_every,
+        )?,
+        schedules: Mutex::new(ScheduleStore::open(
+            data_dir.join("schedules"),
+            snapshot_every,
+        )?),
+    });
+
+    // Check for due standing orders in the background.
+    let scheduler_interval = match std::env::var("SCHEDULER_INTERVAL_SECS") {
+        Ok(value) => match value.parse() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "SCHEDULER_INTERVAL_SECS must be a positive integer",
+                ));
+            }
+        },
+        Err(_) => Duration::from_secs(10),
+    };
+    let scheduler_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(scheduler_interval);
+        loop {
+            interval.tick().await;
+            let state = scheduler_state.clone();
+            if let Err(e) = web::block(move || run_due_schedules(&state)).await {
+                eprintln!("⚠️  Failed to run due schedules: {e}");
+            }
+        }
+    });
+
+    let bind_addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_owned());
+
+    // HTTPS when given a certificate and key, plain HTTP otherwise.
+    let certificate = match (
+        std::env::var("TLS_CERT_PATH"),
+        std::env::var("TLS_KEY_PATH"),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => Some(Arc::new(Certificate::load(
+            cert_path.into(),
+            key_path.into(),
+        )?)),
+        (Err(_), Err(_)) => None,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together",
+            ));
+        }
+    };
+    // Tell browsers to only ever come back over HTTPS. Only sent over HTTPS, as
+    // browsers ignore it on plain HTTP anyway.
+    let hsts = match std::env::var("HSTS_MAX_AGE_SECS") {
+        Ok(_) if certificate.is_none() => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "HSTS_MAX_AGE_SECS needs TLS_CERT_PATH and TLS_KEY_PATH",
+            ));
+        }
+        Ok(value) => match value.parse::<u64>() {
+            Ok(secs) => Some(format!("max-age={secs}")),
+            Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "HSTS_MAX_AGE_SECS must be a non-negative integer",
+                ));
+            }
+        },
+        Err(_) => None,
+    };
+
+    // Pick up renewed certificates without a restart.
+    if let Some(certificate) = certificate.clone() {
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                match certificate.reload_if_changed() {
+                    Ok(true) => println!("🔐 Reloaded the TLS certificate"),
+                    Ok(false) => {}
+                    Err(e) => eprintln!("⚠️  Keeping the current TLS certificate: {e}"),
+                }
+            }
+        });
+    }
+
+    let scheme = if certificate.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    println!("🚀 Server starting at {scheme}://{bind_addr}");
+
+    let server = HttpServer::new(move || {
+        let mut security_headers = DefaultHeaders::new();
+        if let Some(hsts) = &hsts {
+            security_headers =
+                security_headers.add((header::STRICT_TRANSPORT_SECURITY, hsts.as_str()));
+        }
+        App::new()
             .app_data(app_state.clone())
-            .route("/accounts", web::post().to(create_account))
-            .route("/accounts/{id}", web::get().to(get_account))
+            .app_data(authenticator.clone())
+            .app_data(rate_limiter.clone())
+            // Middleware registered last runs first: authenticate, then throttle
+            // by the principal that was found.
+            .wrap(from_fn(rate_limit::throttle))
+            .wrap(from_fn(auth::authenticate))
+            // Outermost, so that every response gets the headers, including the
+            // refusals of the middleware above.
+            .wrap(security_headers)
+            // Requests that cannot even be parsed get the same problem responses as
+            // the ones handlers refuse.
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(|e, _| ApiError::InvalidBody(e.to_string()).into()),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|e, _| ApiError::InvalidQuery(e.to_string()).into()),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(|e, _| ApiError::InvalidPath(e.to_string()).into()),
+            )
+            .service(
+                web::scope("/accounts")
+                    .wrap(from_fn(auth::require_principal))
+                    .route("", web::post().to(create_account))
+                    .route("", web::get().to(list_accounts))
+                    .route("/{id}", web::get().to(get_account))
+                    .route(
+                        "/{id}/transactions",
+                        web::get().to(get_account_transactions),
+                    )
+                    .service(
+                        web::scope("/{id}/holds")
+                            .route("", web::get().to(get_holds))
+                            .route("", web::post().to(place_hold))
+                            .route("/{hold_id}/capture", web::post().to(capture_hold))
+                            .route("/{hold_id}/release", web::post().to(release_hold)),
+                    ),
+            )
             // --- Vulnerable and Secure Paths ---
             .service(
-                web::scope("/vulnerable").route("/transfer", web::post().to(vulnerable_transfer)),
+                web::scope("/vulnerable")
+                    .wrap(Condition::new(
+                        vulnerable_requires_auth,
+                        from_fn(auth::require_principal),
+                    ))
+                    .route("/transfer", web::post().to(vulnerable_transfer))
+                    .route("/accounts/{id}", web::get().to(vulnerable_get_account))
+                    .route("/accounts/{id}/deposit", web::post().to(vulnerable_deposit))
+                    .route(
+                        "/accounts/{id}/withdraw",
+                        web::post().to(vulnerable_withdraw),
+                    ),
             )
-            .service(web::scope("/secure").route("/transfer", web::post().to(secure_transfer)))
-    })
-    .bind(("127.0.0.1", 8080))?
+            .service(
+                web::scope("/secure")
+                    .wrap(from_fn(auth::require_principal))
+                    .route("/transfer", web::post().to(secure_transfer))
+                    .route("/accounts/{id}/deposit", web::post().to(secure_deposit))
+                    .route("/accounts/{id}/withdraw", web::post().to(secure_withdraw))
+                    .route("/transfer/batch", web::post().to(batch_transfer))
+                    .route("/transfers/{id}", web::get().to(get_transfer))
+                    .route("/transfers/{id}/reverse", web::post().to(reverse_transfer)),
+            )
+            .service(
+                web::scope("/schedules")
+                    .wrap(from_fn(auth::require_principal))
+                    .route("", web::post().to(create_schedule))
+                    .route("", web::get().to(list_schedules))
+                    .route("/{id}", web::get().to(get_schedule))
+                    .route("/{id}/cancel", web::post().to(cancel_schedule)),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(auth::require_admin))
+                    .route("/treasury", web::get().to(treasury_supply))
+                    .route("/treasury/mint", web::post().to(mint))
+                    .service(
+                        web::scope("/accounts/{id}")
+                            .route("/freeze", web::post().to(freeze_account))
+                            .route("/unfreeze", web::post().to(unfreeze_account))
+                            .route("/close", web::post().to(close_account))
+                            .route("/overdraft-limit", web::put().to(set_overdraft_limit)),
+                    ),
+            )
+            .service(
+                web::scope("/ledger")
+                    .wrap(from_fn(auth::require_admin))
+                    .route("/entries", web::get().to(ledger_entries))
+                    .route("/reconciliation", web::get().to(ledger_reconciliation)),
+            )
+            .default_service(web::to(|| async {
+                Err::<HttpResponse, _>(ApiError::NotFound(Resource::Route))
+            }))
+    });
+    match certificate {
+        Some(certificate) => server.bind_rustls_0_23(&bind_addr, certificate.server_config()?)?,
+        None => server.bind(&bind_addr)?,
+    }
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::ResponseError;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn state(dir: &Path) -> web::Data<AppState> {
+        let (log, accounts) = EventLog::open(dir.join("vulnerable"), 0).unwrap();
+        web::Data::new(AppState {
+            vulnerable_accounts: Mutex::new(VulnerableStore { accounts, log }),
+            vulnerable_locks: AccountLocks::default(),
+            secure_accounts: Box::new(MemoryStore::open(dir.join("secure"), 0).unwrap()),
+            exchange_rates: RateTable::default(),
+            idempotency: IdempotencyCache::open(
+                dir.join("idempotency"),
+                Duration::from_secs(60),
+                0,
+            )
+            .unwrap(),
+            schedules: Mutex::new(ScheduleStore::open(dir.join("schedules"), 0).unwrap()),
+        })
+    }
+
+    fn alice() -> Principal {
+        Principal {
+            subject: "alice".to_owned(),
+            admin: false,
+        }
+    }
+
+    /// Opens a secure account of alice's with `balance` in it.
+    fn open(data: &AppState, balance: i64) -> Uuid {
+        let account =
+            secure_account::BankAccount::new(Money::from_minor_units(balance), Currency::DEFAULT)
+                .with_owner("alice".to_owned());
+        let id = account.account_number;
+        let opening = JournalEntry::new(EntryKind::AccountOpening, Vec::new()).unwrap();
+        data.secure_accounts.create(account, opening).unwrap();
+        id
+    }
+
+    fn batch(legs: &[(Uuid, Uuid, i64)]) -> BatchTransferRequest {
+        BatchTransferRequest {
+            legs: legs
+                .iter()
+                .map(|&(from_account, to_account, amount)| TransferRequest {
+                    from_account,
+                    to_account,
+                    amount: Money::from_minor_units(amount),
+                    convert: false,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn a_failing_leg_rolls_back_every_leg_of_a_batch() {
+        let dir = TempDir::new().unwrap();
+        let data = state(dir.path());
+        let [a, b, c] = [open(&data, 100), open(&data, 0), open(&data, 0)];
+        let before = data.secure_accounts.list().unwrap();
+        let journal = data.secure_accounts.journal().unwrap().len();
+
+        // The first two legs would pass on their own; the third overdraws `b`.
+        let req = batch(&[(a, b, 60), (b, c, 10), (b, c, 100)]);
+        let response = perform_batch_transfer(&data, &alice(), &req).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let after = data.secure_accounts.list().unwrap();
+        for account in &before {
+            let now = after
+                .iter()
+                .find(|a| a.account_number == account.account_number)
+                .unwrap();
+            assert_eq!(now.balance(), account.balance());
+            assert_eq!(now.version(), account.version());
+        }
+        assert_eq!(data.secure_accounts.journal().unwrap().len(), journal);
+    }
+
+    fn account_request(initial_balance: i64) -> CreateAccountRequest {
+        serde_json::from_value(serde_json::json!({ "initial_balance": initial_balance })).unwrap()
+    }
+
+    #[test]
+    fn a_refused_secure_account_takes_its_vulnerable_twin_with_it() {
+        let dir = TempDir::new().unwrap();
+        let data = state(dir.path());
+
+        // Nothing was minted, so the treasury cannot pay the opening balance.
+        let error = open_account(&data, &alice(), &account_request(100)).unwrap_err();
+        assert_eq!(error.code(), "treasury_lacks_funds");
+        assert!(data.vulnerable_accounts.lock().unwrap().accounts.is_empty());
+        assert!(data.secure_accounts.list().unwrap().is_empty());
+
+        // Nor does the account come back after a restart.
+        drop(data);
+        let (_, accounts): (_, HashMap<Uuid, vulnerable_account::BankAccount>) =
+            EventLog::open(dir.path().join("vulnerable"), 0).unwrap();
+        assert!(accounts.is_empty());
+    }
+
+    #[test]
+    fn an_opened_account_is_in_both_stores_under_one_number() {
+        let dir = TempDir::new().unwrap();
+        let data = state(dir.path());
+        open_account(&data, &alice(), &account_request(0)).unwrap();
+
+        let secure = data.secure_accounts.list().unwrap();
+        let vulnerable = data.vulnerable_accounts.lock().unwrap();
+        assert_eq!(secure.len(), 1);
+        assert_eq!(vulnerable.accounts.len(), 1);
+        assert!(vulnerable.accounts.contains_key(&secure[0].account_number));
+    }
+
+    #[actix_web::test]
+    async fn a_batch_refuses_if_match() {
+        let dir = TempDir::new().unwrap();
+        let data = state(dir.path());
+        let [a, b] = [open(&data, 100), open(&data, 0)];
+        let req = TestRequest::post()
+            .insert_header((header::IF_MATCH, "\"0\""))
+            .to_http_request();
+
+        let error = batch_transfer(data.clone(), alice(), req, ValidJson(batch(&[(a, b, 10)])))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), "if_match_not_supported");
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            data.secure_accounts.get(a).unwrap().balance(),
+            Money::from_minor_units(100)
+        );
+    }
+
+    #[actix_web::test]
+    async fn a_response_built_off_the_workers_keeps_its_headers_and_body() {
+        let dir = TempDir::new().unwrap();
+        let data = state(dir.path());
+        let id = open(&data, 100);
+
+        let response = get_account(data.clone(), alice(), web::Path::from(id))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::ETAG).unwrap(), "\"0\"");
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let account: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(account["account_number"], id.to_string());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::error::DomainError;
+use serde::{Deserialize, Serialize};
+use validator::ValidateRange;
+
+/// An amount of money stored as a signed count of minor units (e.g. cents).
+///
+/// Arithmetic is only available through the checked methods, so an overflow
+/// surfaces as an error that handlers can reject instead of wrapping around
+/// (release builds) or panicking (debug builds).
+///
+/// The value is signed on purpose: the vulnerable module still needs to be able
+/// to drive a balance below zero for the lesson to work, and a secure account
+/// with an overdraft limit may legitimately hold a negative balance.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub const fn from_minor_units(minor_units: i64) -> Self {
+        Self(minor_units)
+    }
+
+    pub const fn minor_units(self) -> i64 {
+        self.0
+    }
+
+    pub const fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    pub const fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Adds two amounts, failing instead of overflowing.
+    pub fn checked_add(self, other: Money) -> Result<Money, DomainError> {
+        self.0
+            .checked_add(other.0)
+            .map(Money)
+            .ok_or(DomainError::AmountOverflow)
+    }
+
+    /// Subtracts `other` from `self`, failing instead of overflowing.
+    pub fn checked_sub(self, other: Money) -> Result<Money, DomainError> {
+        self.0
+            .checked_sub(other.0)
+            .map(Money)
+            .ok_or(DomainError::AmountOverflow)
+    }
+}
+
+/// Lets request types bound amounts with `#[validate(range(...))]`.
+impl ValidateRange<Money> for Money {
+    fn greater_than(&self, max: Money) -> Option<bool> {
+        Some(*self > max)
+    }
+
+    fn less_than(&self, min: Money) -> Option<bool> {
+        Some(*self < min)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_arithmetic_fails_instead_of_overflowing() {
+        let max = Money::from_minor_units(i64::MAX);
+        let min = Money::from_minor_units(i64::MIN);
+        let one = Money::from_minor_units(1);
+        assert_eq!(max.checked_add(one), Err(DomainError::AmountOverflow));
+        assert_eq!(min.checked_sub(one), Err(DomainError::AmountOverflow));
+        assert_eq!(
+            Money::ZERO.checked_sub(min),
+            Err(DomainError::AmountOverflow)
+        );
+        assert_eq!(
+            max.checked_sub(one),
+            Ok(Money::from_minor_units(i64::MAX - 1))
+        );
+    }
+
+    #[test]
+    fn arithmetic_may_go_below_zero() {
+        let thirty = Money::from_minor_units(30);
+        let fifty = Money::from_minor_units(50);
+        let result = thirty.checked_sub(fifty).unwrap();
+        assert_eq!(result, Money::from_minor_units(-20));
+        assert!(result.is_negative());
+        assert!(!result.is_positive());
+        assert_eq!(result.checked_add(fifty), Ok(thirty));
+        assert!(!Money::ZERO.is_positive() && !Money::ZERO.is_negative());
+    }
+
+    #[test]
+    fn amounts_are_the_same_bare_integers_the_i32_balances_were() {
+        // Bodies and logs written when balances were `i32` read back unchanged,
+        // as minor units.
+        for old in [0, 100, -250, i32::MAX, i32::MIN] {
+            let money: Money = serde_json::from_str(&old.to_string()).unwrap();
+            assert_eq!(money.minor_units(), i64::from(old));
+            assert_eq!(serde_json::to_string(&money).unwrap(), old.to_string());
+        }
+    }
+
+    #[test]
+    fn sums_past_the_old_i32_range_no_longer_overflow() {
+        let max = Money::from_minor_units(i64::from(i32::MAX));
+        let sum = max.checked_add(max).unwrap();
+        assert_eq!(sum.minor_units(), 2 * i64::from(i32::MAX));
+    }
+
+    #[test]
+    fn fractional_amounts_are_refused() {
+        assert!(serde_json::from_str::<Money>("1.5").is_err());
+    }
+}
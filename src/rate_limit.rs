@@ -0,0 +1,501 @@
+use crate::auth::Principal;
+use crate::error::ApiError;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, web};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Buckets are not purged while there are fewer than this many.
+const MIN_PURGE_AT: usize = 1024;
+
+/// Who a bucket belongs to: the principal if the request has one, so one
+/// client gets one allowance wherever it connects from, else the peer address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Principal(String),
+    Address(Option<IpAddr>),
+}
+
+/// Which allowance a bucket draws on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Allowance {
+    /// The rule at this index.
+    Route(usize),
+    /// Attempts with wrong credentials.
+    FailedAuth,
+}
+
+/// How big a bucket is and how fast it refills.
+struct Rate {
+    /// How many requests may be sent back to back.
+    burst: f64,
+    /// How many requests per second are added back, up to `burst`.
+    per_second: f64,
+}
+
+/// One route line of the limits file.
+struct Rule {
+    /// `None` matches any method.
+    method: Option<Method>,
+    /// A route pattern as registered, e.g. `/accounts/{id}/deposit`. `None`
+    /// matches any route, including paths that match none.
+    route: Option<String>,
+    rate: Rate,
+}
+
+impl Rule {
+    fn matches(&self, method: &Method, route: Option<&str>) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method)
+            && self.route.as_deref().is_none_or(|r| Some(r) == route)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Adds the tokens that have come back since the last update.
+    fn refill(&mut self, rate: &Rate, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst);
+        self.updated = now;
+    }
+
+    /// How many whole seconds until the next token, if there is none now.
+    fn wait(&self, rate: &Rate) -> Result<(), u64> {
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(((1.0 - self.tokens) / rate.per_second).ceil() as u64)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_client: HashMap<(Allowance, Client), Bucket>,
+    /// Full buckets are dropped once the map grows past this size.
+    purge_at: usize,
+}
+
+/// Token-bucket rate limits per route and per client, kept in memory. Every
+/// rule gives each client its own bucket of `burst` tokens, refilled at a
+/// steady rate; a request takes one token, and is refused with 429 if there is
+/// none. Restarting the server refills every bucket.
+///
+/// Wrong credentials draw on an allowance of their own, per peer address; see
+/// [`RateLimiter::check_failed_auth`].
+#[derive(Default)]
+pub struct RateLimiter {
+    rules: Vec<Rule>,
+    failed_auth: Option<Rate>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Loads rules from a text file with one `METHOD ROUTE BURST RATE` per line,
+    /// where `RATE` is `N/s`, `N/min` or `N/h` and `*` stands for any method or
+    /// route. The first matching line applies to a request. A single
+    /// `failed-auth BURST RATE` line limits attempts with wrong credentials. Blank
+    /// lines and lines starting with `#` are ignored.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let mut rules = Vec::new();
+        let mut failed_auth = None;
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {reason}", path.display(), index + 1),
+                )
+            };
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if let ["failed-auth", burst, rate] = fields[..] {
+                if failed_auth.is_some() {
+                    return Err(invalid("`failed-auth` may only be given once"));
+                }
+                failed_auth = Some(parse_rate(burst, rate).map_err(&invalid)?);
+                continue;
+            }
+            let [method, route, burst, rate] = fields[..] else {
+                return Err(invalid("expected METHOD ROUTE BURST RATE"));
+            };
+            let method = match method {
+                "*" => None,
+                method => Some(
+                    Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                        .map_err(|_| invalid("invalid method"))?,
+                ),
+            };
+            let route = match route {
+                "*" => None,
+                route if route.starts_with('/') => Some(route.to_owned()),
+                _ => return Err(invalid("a route must start with `/` or be `*`")),
+            };
+            let rate = parse_rate(burst, rate).map_err(invalid)?;
+            rules.push(Rule {
+                method,
+                route,
+                rate,
+            });
+        }
+        Ok(Self {
+            rules,
+            failed_auth,
+            buckets: Mutex::default(),
+        })
+    }
+
+    fn rate(&self, allowance: Allowance) -> &Rate {
+        match allowance {
+            Allowance::Route(index) => &self.rules[index].rate,
+            Allowance::FailedAuth => self
+                .failed_auth
+                .as_ref()
+                .expect("failed-auth buckets exist only with a failed-auth limit"),
+        }
+    }
+
+    /// Takes a token for a request arriving at `now`, or says how many whole
+    /// seconds to wait until one is available. Requests that match no rule are
+    /// not limited.
+    fn acquire(
+        &self,
+        method: &Method,
+        route: Option<&str>,
+        client: Client,
+        now: Instant,
+    ) -> Result<(), u64> {
+        let Some(index) = self
+            .rules
+            .iter()
+            .position(|rule| rule.matches(method, route))
+        else {
+            return Ok(());
+        };
+        let rate = &self.rules[index].rate;
+        self.with_bucket(Allowance::Route(index), client, now, |bucket| {
+            bucket.wait(rate)?;
+            bucket.tokens -= 1.0;
+            Ok(())
+        })
+    }
+
+    /// Whether the peer at `address` may still present credentials, or how many
+    /// whole seconds it must wait because too many of its attempts were wrong.
+    /// Checked before the credentials are, so that a client that was cut off
+    /// cannot keep guessing and tell a right guess by the answer.
+    pub fn check_failed_auth(&self, address: Option<IpAddr>, now: Instant) -> Result<(), u64> {
+        let Some(rate) = &self.failed_auth else {
+            return Ok(());
+        };
+        let key = (Allowance::FailedAuth, Client::Address(address));
+        match self.buckets.lock().unwrap().by_client.get_mut(&key) {
+            Some(bucket) => {
+                bucket.refill(rate, now);
+                bucket.wait(rate)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Charges an attempt with wrong credentials to the peer at `address`.
+    pub fn record_failed_auth(&self, address: Option<IpAddr>, now: Instant) {
+        if self.failed_auth.is_none() {
+            return;
+        }
+        let client = Client::Address(address);
+        self.with_bucket(Allowance::FailedAuth, client, now, |bucket| {
+            // Attempts racing past the check may find the bucket empty already.
+            bucket.tokens = (bucket.tokens - 1.0).max(0.0);
+        });
+    }
+
+    /// Runs `f` on the client's bucket for `allowance`, refilled up to `now`. A
+    /// client without one starts with a full bucket.
+    fn with_bucket<T>(
+        &self,
+        allowance: Allowance,
+        client: Client,
+        now: Instant,
+        f: impl FnOnce(&mut Bucket) -> T,
+    ) -> T {
+        let rate = self.rate(allowance);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.by_client.len() >= buckets.purge_at.max(MIN_PURGE_AT) {
+            self.purge_full(&mut buckets, now);
+        }
+        let bucket = buckets
+            .by_client
+            .entry((allowance, client))
+            .or_insert(Bucket {
+                tokens: rate.burst,
+                updated: now,
+            });
+        bucket.refill(rate, now);
+        f(bucket)
+    }
+
+    /// Drops buckets that have refilled completely, as a fresh one would be just
+    /// the same. Runs again once the map has doubled, so it costs O(1) per request.
+    fn purge_full(&self, buckets: &mut Buckets, now: Instant) {
+        buckets.by_client.retain(|(allowance, _), bucket| {
+            let rate = self.rate(*allowance);
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * rate.per_second < rate.burst
+        });
+        buckets.purge_at = buckets.by_client.len() * 2;
+    }
+}
+
+/// The address a request came from, for limits per address: the peer itself,
+/// not a forwarded-for header anyone could make up.
+pub fn peer_address(req: &ServiceRequest) -> Option<IpAddr> {
+    req.peer_addr().map(|addr| addr.ip())
+}
+
+/// Middleware for the whole app; must run after [`crate::auth::authenticate`] so
+/// that it can tell clients apart by principal.
+pub async fn throttle<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, actix_web::Error> {
+    let limiter = req
+        .app_data::<web::Data<RateLimiter>>()
+        .expect("the rate limiter is registered as app data");
+    let client = match req.extensions().get::<Principal>() {
+        Some(principal) => Client::Principal(principal.subject.clone()),
+        None => Client::Address(peer_address(&req)),
+    };
+    let route = req.match_pattern();
+    let acquired = limiter.acquire(req.method(), route.as_deref(), client, Instant::now());
+    if let Err(retry_after) = acquired {
+        return Ok(ApiError::RateLimited { retry_after }.refuse(req));
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Parses the `BURST RATE` at the end of a line.
+fn parse_rate(burst: &str, rate: &str) -> Result<Rate, &'static str> {
+    let burst = match burst.parse::<u32>() {
+        Ok(burst) if burst > 0 => f64::from(burst),
+        _ => return Err("the burst must be a positive integer"),
+    };
+    let per_second = rate
+        .split_once('/')
+        .and_then(|(count, unit)| {
+            let count = count.parse::<u32>().ok().filter(|&count| count > 0)?;
+            let seconds = match unit {
+                "s" => 1.0,
+                "min" => 60.0,
+                "h" => 3600.0,
+                _ => return None,
+            };
+            Some(f64::from(count) / seconds)
+        })
+        .ok_or("the rate must be N/s, N/min or N/h with N positive")?;
+    Ok(Rate { burst, per_second })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Duration;
+    use tempfile::NamedTempFile;
+
+    fn load(contents: &str) -> io::Result<RateLimiter> {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        RateLimiter::load(file.path())
+    }
+
+    fn alice() -> Client {
+        Client::Principal("alice".to_owned())
+    }
+
+    fn address(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([192, 0, 2, last]))
+    }
+
+    fn after(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn load_reads_rules_and_skips_comments() {
+        let limiter =
+            load("# comment\n\nPOST /accounts 5 10/min\n* * 100 1/s\nfailed-auth 3 1/h\n").unwrap();
+        assert_eq!(limiter.rules.len(), 2);
+        assert_eq!(limiter.rules[0].method, Some(Method::POST));
+        assert_eq!(limiter.rules[0].route.as_deref(), Some("/accounts"));
+        assert_eq!(limiter.rules[0].rate.burst, 5.0);
+        assert_eq!(limiter.rules[0].rate.per_second, 10.0 / 60.0);
+        assert_eq!(limiter.rules[1].method, None);
+        assert_eq!(limiter.rules[1].route, None);
+        let failed_auth = limiter.failed_auth.unwrap();
+        assert_eq!(failed_auth.burst, 3.0);
+        assert_eq!(failed_auth.per_second, 1.0 / 3600.0);
+    }
+
+    #[test]
+    fn load_names_the_bad_line() {
+        for (contents, reason) in [
+            ("POST /accounts 5\n", "expected METHOD ROUTE BURST RATE"),
+            ("POST accounts 5 1/s\n", "a route must start with `/`"),
+            (
+                "POST /accounts 0 1/s\n",
+                "the burst must be a positive integer",
+            ),
+            ("POST /accounts 5 1/day\n", "the rate must be"),
+            ("POST /accounts 5 0/s\n", "the rate must be"),
+        ] {
+            let error = load(&format!("# limits\n{contents}")).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            let message = error.to_string();
+            assert!(message.contains(":2: "), "{message}");
+            assert!(message.contains(reason), "{message}");
+        }
+
+        let error = load("failed-auth 1 1/s\n\nfailed-auth 1 1/s\n")
+            .err()
+            .unwrap();
+        let message = error.to_string();
+        assert!(message.contains(":3: "), "{message}");
+        assert!(message.contains("may only be given once"), "{message}");
+    }
+
+    #[test]
+    fn the_first_matching_rule_applies_and_unmatched_requests_are_free() {
+        let limiter = load("POST /accounts 1 1/h\n* /accounts 3 1/h\n").unwrap();
+        let now = Instant::now();
+        let acquire = |method: &Method, route| limiter.acquire(method, route, alice(), now);
+
+        assert_eq!(acquire(&Method::POST, Some("/accounts")), Ok(()));
+        assert!(acquire(&Method::POST, Some("/accounts")).is_err());
+        // GET falls through to the second rule, with a bucket of its own.
+        for _ in 0..3 {
+            assert_eq!(acquire(&Method::GET, Some("/accounts")), Ok(()));
+        }
+        assert!(acquire(&Method::GET, Some("/accounts")).is_err());
+        for _ in 0..10 {
+            assert_eq!(acquire(&Method::POST, Some("/secure/transfer")), Ok(()));
+            assert_eq!(acquire(&Method::GET, None), Ok(()));
+        }
+    }
+
+    #[test]
+    fn an_empty_bucket_says_when_to_retry_and_refills_over_time() {
+        let limiter = load("POST /secure/transfer 2 1/s\n").unwrap();
+        let route = Some("/secure/transfer");
+        let start = Instant::now();
+        let acquire = |at| limiter.acquire(&Method::POST, route, alice(), at);
+
+        assert_eq!(acquire(start), Ok(()));
+        assert_eq!(acquire(start), Ok(()));
+        assert_eq!(acquire(start), Err(1));
+        // Refilling a token takes a second; a fraction of one rounds up.
+        assert_eq!(acquire(after(start, 400)), Err(1));
+        assert_eq!(acquire(after(start, 1000)), Ok(()));
+        assert_eq!(acquire(after(start, 1000)), Err(1));
+        // A long wait refills only up to the burst.
+        let later = after(start, 60_000);
+        assert_eq!(acquire(later), Ok(()));
+        assert_eq!(acquire(later), Ok(()));
+        assert_eq!(acquire(later), Err(1));
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        let limiter = load("POST /accounts 1 10/min\n").unwrap();
+        let now = Instant::now();
+        let acquire = || limiter.acquire(&Method::POST, Some("/accounts"), alice(), now);
+        assert_eq!(acquire(), Ok(()));
+        assert_eq!(acquire(), Err(6));
+    }
+
+    #[test]
+    fn clients_have_buckets_of_their_own() {
+        let limiter = load("* * 1 1/h\n").unwrap();
+        let now = Instant::now();
+        let acquire = |client| limiter.acquire(&Method::GET, None, client, now);
+
+        assert_eq!(acquire(alice()), Ok(()));
+        assert!(acquire(alice()).is_err());
+        assert_eq!(acquire(Client::Principal("bob".to_owned())), Ok(()));
+        assert_eq!(acquire(Client::Address(address(1))), Ok(()));
+        assert!(acquire(Client::Address(address(1))).is_err());
+        assert_eq!(acquire(Client::Address(address(2))), Ok(()));
+    }
+
+    #[test]
+    fn wrong_credentials_use_up_an_allowance_per_address() {
+        let limiter = load("failed-auth 2 1/min\n").unwrap();
+        let start = Instant::now();
+
+        assert_eq!(limiter.check_failed_auth(address(1), start), Ok(()));
+        limiter.record_failed_auth(address(1), start);
+        assert_eq!(limiter.check_failed_auth(address(1), start), Ok(()));
+        limiter.record_failed_auth(address(1), start);
+        assert_eq!(limiter.check_failed_auth(address(1), start), Err(60));
+        assert_eq!(limiter.check_failed_auth(address(2), start), Ok(()));
+
+        // Checking alone does not use the allowance up further.
+        assert_eq!(
+            limiter.check_failed_auth(address(1), after(start, 30_000)),
+            Err(30)
+        );
+        assert_eq!(
+            limiter.check_failed_auth(address(1), after(start, 60_000)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn without_a_failed_auth_line_wrong_credentials_are_not_limited() {
+        let limiter = load("* * 1 1/h\n").unwrap();
+        let now = Instant::now();
+        for _ in 0..10 {
+            limiter.record_failed_auth(address(1), now);
+        }
+        assert_eq!(limiter.check_failed_auth(address(1), now), Ok(()));
+        assert!(limiter.buckets.lock().unwrap().by_client.is_empty());
+    }
+
+    #[test]
+    fn full_buckets_are_purged_once_there_are_many() {
+        let limiter = load("* * 2 1/s\n").unwrap();
+        let start = Instant::now();
+        for client in 0..MIN_PURGE_AT {
+            let client = Client::Principal(client.to_string());
+            limiter.acquire(&Method::GET, None, client, start).unwrap();
+        }
+        assert_eq!(
+            limiter.buckets.lock().unwrap().by_client.len(),
+            MIN_PURGE_AT
+        );
+
+        // Every bucket has refilled by now, so they all go but the new one.
+        let client = Client::Principal("late".to_owned());
+        limiter
+            .acquire(&Method::GET, None, client, after(start, 5_000))
+            .unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().by_client.len(), 1);
+    }
+}
@@ -0,0 +1,512 @@
+use crate::error::DomainError;
+use crate::events::{EventLog, EventSourced};
+use crate::money::Money;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use uuid::Uuid;
+
+/// How many past runs a schedule remembers; older ones are dropped.
+const MAX_RECORDED_RUNS: usize = 50;
+
+/// When a standing order repeats. All times are UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Recurrence {
+    Once,
+    Daily,
+    Weekly,
+    /// On day `day` of every month, or on the last day of months that are shorter.
+    Monthly {
+        day: u32,
+    },
+}
+
+impl Recurrence {
+    pub fn validate(self) -> Result<(), DomainError> {
+        match self {
+            Recurrence::Monthly { day } if !(1..=31).contains(&day) => {
+                Err(DomainError::InvalidDayOfMonth)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The first run at or after `start`.
+    fn first_at(self, start: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Recurrence::Monthly { day } => {
+                let candidate = on_day_of_month(start, start.year(), start.month(), day);
+                if candidate >= start {
+                    candidate
+                } else {
+                    add_month(start, day)
+                }
+            }
+            _ => start,
+        }
+    }
+
+    /// The run after one at `previous`, or `None` if the order does not repeat.
+    fn next_after(self, previous: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Recurrence::Once => None,
+            Recurrence::Daily => Some(previous + Duration::days(1)),
+            Recurrence::Weekly => Some(previous + Duration::weeks(1)),
+            Recurrence::Monthly { day } => Some(add_month(previous, day)),
+        }
+    }
+}
+
+/// `at`'s time of day on day `day` of the given month, moved back to the
+/// month's last day if it has fewer days.
+fn on_day_of_month(at: DateTime<Utc>, year: i32, month: u32, day: u32) -> DateTime<Utc> {
+    let date = (1..=day)
+        .rev()
+        .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+        .expect("day 1 exists in every month");
+    date.and_time(at.time()).and_utc()
+}
+
+/// Day `day` of the month after `at`'s month.
+fn add_month(at: DateTime<Utc>, day: u32) -> DateTime<Utc> {
+    let (year, month) = match at.month() {
+        12 => (at.year() + 1, 1),
+        month => (at.year(), month + 1),
+    };
+    on_day_of_month(at, year, month, day)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleStatus {
+    Active,
+    /// Ran for the last time, or reached its end date.
+    Completed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum RunOutcome {
+    Succeeded {
+        journal_entry: Uuid,
+    },
+    /// The transfer was refused, e.g. for insufficient funds. The schedule
+    /// carries on with its next run.
+    Failed {
+        reason: String,
+    },
+}
+
+/// One execution of a standing order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Run {
+    pub scheduled_for: DateTime<Utc>,
+    pub executed_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub outcome: RunOutcome,
+}
+
+/// A standing order: a secure transfer executed at a set time, once or repeatedly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: Uuid,
+    pub from_account: Uuid,
+    pub to_account: Uuid,
+    /// Amount in the sender's currency, as in a secure transfer.
+    pub amount: Money,
+    pub convert: bool,
+    pub recurrence: Recurrence,
+    /// No run is scheduled after this time.
+    pub end_at: Option<DateTime<Utc>>,
+    pub status: ScheduleStatus,
+    /// When the next run is due; `None` once the schedule is no longer active.
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// The most recent runs, oldest first.
+    pub runs: Vec<Run>,
+}
+
+/// What a new standing order should do; everything else is filled in on creation.
+pub struct NewSchedule {
+    pub from_account: Uuid,
+    pub to_account: Uuid,
+    pub amount: Money,
+    pub convert: bool,
+    pub recurrence: Recurrence,
+    pub start_at: DateTime<Utc>,
+    pub end_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Created {
+        schedule: Schedule,
+    },
+    /// A run was taken off the schedule and is about to execute. Logged before
+    /// the transfer, so a crash in between skips the run rather than repeating it.
+    Advanced {
+        id: Uuid,
+        next_run_at: Option<DateTime<Utc>>,
+    },
+    RunRecorded {
+        id: Uuid,
+        run: Run,
+    },
+    Cancelled {
+        id: Uuid,
+    },
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Schedules {
+    by_id: HashMap<Uuid, Schedule>,
+}
+
+impl EventSourced for Schedules {
+    type Event = Event;
+
+    fn apply(&mut self, event: Event) -> Result<(), String> {
+        match event {
+            Event::Created { schedule } => {
+                self.by_id.insert(schedule.id, schedule);
+            }
+            Event::Advanced { id, next_run_at } => {
+                let schedule = self.get_mut(id)?;
+                schedule.next_run_at = next_run_at;
+                if next_run_at.is_none() {
+                    schedule.status = ScheduleStatus::Completed;
+                }
+            }
+            Event::RunRecorded { id, run } => {
+                let runs = &mut self.get_mut(id)?.runs;
+                runs.push(run);
+                if runs.len() > MAX_RECORDED_RUNS {
+                    runs.remove(0);
+                }
+            }
+            Event::Cancelled { id } => {
+                let schedule = self.get_mut(id)?;
+                schedule.status = ScheduleStatus::Cancelled;
+                schedule.next_run_at = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Schedules {
+    fn get_mut(&mut self, id: Uuid) -> Result<&mut Schedule, String> {
+        self.by_id
+            .get_mut(&id)
+            .ok_or_else(|| format!("unknown schedule {id}"))
+    }
+}
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    NotFound,
+    Rejected(DomainError),
+    /// The schedule log could not be written.
+    Log(io::Error),
+}
+
+impl From<io::Error> for ScheduleError {
+    fn from(e: io::Error) -> Self {
+        ScheduleError::Log(e)
+    }
+}
+
+/// Standing orders, made durable by their own event log.
+pub struct ScheduleStore {
+    schedules: Schedules,
+    log: EventLog<Schedules>,
+}
+
+impl ScheduleStore {
+    pub fn open(dir: impl AsRef<Path>, snapshot_every: u64) -> io::Result<Self> {
+        let (log, schedules) = EventLog::open(dir, snapshot_every)?;
+        Ok(Self { schedules, log })
+    }
+
+    /// Logs `event` and then applies it.
+    fn commit(&mut self, event: Event) -> Result<(), ScheduleError> {
+        self.log.append(&event)?;
+        self.schedules
+            .apply(event)
+            .map_err(|e| ScheduleError::Log(io::Error::other(e)))?;
+        if let Err(e) = self.log.snapshot_if_due(&self.schedules) {
+            eprintln!("⚠️  Failed to write snapshot: {e}");
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<&Schedule> {
+        self.schedules.by_id.get(&id)
+    }
+
+    /// Every schedule, oldest first.
+    pub fn list(&self) -> Vec<&Schedule> {
+        let mut schedules: Vec<_> = self.schedules.by_id.values().collect();
+        schedules.sort_by_key(|schedule| schedule.created_at);
+        schedules
+    }
+
+    pub fn create(&mut self, new: NewSchedule) -> Result<Schedule, ScheduleError> {
+        new.recurrence.validate().map_err(ScheduleError::Rejected)?;
+        if !new.amount.is_positive() {
+            return Err(ScheduleError::Rejected(DomainError::InvalidAmount));
+        }
+        if new.from_account == new.to_account {
+            return Err(ScheduleError::Rejected(DomainError::SameAccount));
+        }
+        let first_run = new.recurrence.first_at(new.start_at);
+        if new.end_at.is_some_and(|end_at| end_at < first_run) {
+            return Err(ScheduleError::Rejected(
+                DomainError::ScheduleEndsBeforeFirstRun,
+            ));
+        }
+
+        let schedule = Schedule {
+            id: Uuid::new_v4(),
+            from_account: new.from_account,
+            to_account: new.to_account,
+            amount: new.amount,
+            convert: new.convert,
+            recurrence: new.recurrence,
+            end_at: new.end_at,
+            status: ScheduleStatus::Active,
+            next_run_at: Some(first_run),
+            created_at: Utc::now(),
+            runs: Vec::new(),
+        };
+        self.commit(Event::Created {
+            schedule: schedule.clone(),
+        })?;
+        Ok(schedule)
+    }
+
+    pub fn cancel(&mut self, id: Uuid) -> Result<Schedule, ScheduleError> {
+        match self.get(id) {
+            None => return Err(ScheduleError::NotFound),
+            Some(schedule) if schedule.status != ScheduleStatus::Active => {
+                return Err(ScheduleError::Rejected(DomainError::ScheduleNotActive));
+            }
+            Some(_) => {}
+        }
+        self.commit(Event::Cancelled { id })?;
+        Ok(self
+            .get(id)
+            .expect("the schedule was just cancelled")
+            .clone())
+    }
+
+    /// Active schedules whose next run is due at `now`.
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<Schedule> {
+        self.schedules
+            .by_id
+            .values()
+            .filter(|schedule| {
+                schedule.status == ScheduleStatus::Active
+                    && schedule.next_run_at.is_some_and(|at| at <= now)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Moves a due schedule on to its next run. Runs missed while the server
+    /// was down are not caught up one by one: the next run is the first one
+    /// after `now`.
+    pub fn advance(&mut self, id: Uuid, now: DateTime<Utc>) -> Result<(), ScheduleError> {
+        let schedule = self.get(id).ok_or(ScheduleError::NotFound)?;
+        let mut next = schedule.next_run_at;
+        while let Some(at) = next
+            && at <= now
+        {
+            next = schedule.recurrence.next_after(at);
+        }
+        let next_run_at = next.filter(|at| schedule.end_at.is_none_or(|end_at| *at <= end_at));
+        self.commit(Event::Advanced { id, next_run_at })
+    }
+
+    pub fn record_run(&mut self, id: Uuid, run: Run) -> Result<(), ScheduleError> {
+        self.commit(Event::RunRecorded { id, run })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    /// A store in a fresh directory of its own, removed when the guard drops.
+    fn store() -> (TempDir, ScheduleStore) {
+        let dir = TempDir::new().unwrap();
+        let store = ScheduleStore::open(dir.path(), 0).unwrap();
+        (dir, store)
+    }
+
+    fn create(
+        store: &mut ScheduleStore,
+        recurrence: Recurrence,
+        start_at: &str,
+        end_at: Option<&str>,
+    ) -> Result<Schedule, ScheduleError> {
+        store.create(NewSchedule {
+            from_account: Uuid::new_v4(),
+            to_account: Uuid::new_v4(),
+            amount: Money::from_minor_units(100),
+            convert: false,
+            recurrence,
+            start_at: at(start_at),
+            end_at: end_at.map(at),
+        })
+    }
+
+    #[test]
+    fn monthly_on_the_31st_uses_the_last_day_of_february_and_then_the_31st_again() {
+        let monthly = Recurrence::Monthly { day: 31 };
+        let first = monthly.first_at(at("2027-01-31T09:00:00Z"));
+        assert_eq!(first, at("2027-01-31T09:00:00Z"));
+        let february = monthly.next_after(first).unwrap();
+        assert_eq!(february, at("2027-02-28T09:00:00Z"));
+        assert_eq!(
+            monthly.next_after(february).unwrap(),
+            at("2027-03-31T09:00:00Z")
+        );
+        // Leap years have a 29th.
+        assert_eq!(
+            monthly.next_after(at("2028-01-31T09:00:00Z")).unwrap(),
+            at("2028-02-29T09:00:00Z")
+        );
+    }
+
+    #[test]
+    fn monthly_rolls_over_into_january_of_the_next_year() {
+        let monthly = Recurrence::Monthly { day: 31 };
+        assert_eq!(
+            monthly.next_after(at("2026-12-31T09:00:00Z")).unwrap(),
+            at("2027-01-31T09:00:00Z")
+        );
+        // A start after this month's day waits for next month's.
+        assert_eq!(
+            Recurrence::Monthly { day: 10 }.first_at(at("2026-12-15T08:30:00Z")),
+            at("2027-01-10T08:30:00Z")
+        );
+    }
+
+    #[test]
+    fn monthly_first_run_is_this_month_if_the_day_is_still_ahead() {
+        assert_eq!(
+            Recurrence::Monthly { day: 31 }.first_at(at("2027-02-03T12:00:00Z")),
+            at("2027-02-28T12:00:00Z")
+        );
+    }
+
+    #[test]
+    fn advance_after_an_outage_skips_to_the_first_run_after_now() {
+        let (_dir, mut store) = store();
+        let schedule = create(&mut store, Recurrence::Daily, "2027-01-01T09:00:00Z", None).unwrap();
+        store
+            .advance(schedule.id, at("2027-01-10T12:00:00Z"))
+            .unwrap();
+        let schedule = store.get(schedule.id).unwrap();
+        assert_eq!(schedule.status, ScheduleStatus::Active);
+        assert_eq!(schedule.next_run_at, Some(at("2027-01-11T09:00:00Z")));
+    }
+
+    #[test]
+    fn advance_after_an_outage_past_the_end_date_completes_the_schedule() {
+        let (_dir, mut store) = store();
+        let schedule = create(
+            &mut store,
+            Recurrence::Monthly { day: 31 },
+            "2027-01-31T09:00:00Z",
+            Some("2027-04-30T00:00:00Z"),
+        )
+        .unwrap();
+        // Down from February to May: the March and April runs are not caught up,
+        // and the next one would be after the end date.
+        store
+            .advance(schedule.id, at("2027-05-02T00:00:00Z"))
+            .unwrap();
+        let schedule = store.get(schedule.id).unwrap();
+        assert_eq!(schedule.status, ScheduleStatus::Completed);
+        assert_eq!(schedule.next_run_at, None);
+    }
+
+    #[test]
+    fn advance_keeps_a_run_that_falls_on_the_end_date() {
+        let (_dir, mut store) = store();
+        let schedule = create(
+            &mut store,
+            Recurrence::Weekly,
+            "2027-03-01T09:00:00Z",
+            Some("2027-03-08T09:00:00Z"),
+        )
+        .unwrap();
+        store
+            .advance(schedule.id, at("2027-03-01T09:00:00Z"))
+            .unwrap();
+        assert_eq!(
+            store.get(schedule.id).unwrap().next_run_at,
+            Some(at("2027-03-08T09:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn a_one_off_order_completes_after_its_run() {
+        let (_dir, mut store) = store();
+        let schedule = create(&mut store, Recurrence::Once, "2027-03-01T09:00:00Z", None).unwrap();
+        store
+            .advance(schedule.id, at("2027-03-01T09:00:01Z"))
+            .unwrap();
+        assert_eq!(
+            store.get(schedule.id).unwrap().status,
+            ScheduleStatus::Completed
+        );
+    }
+
+    #[test]
+    fn create_refuses_an_end_before_the_first_run() {
+        let (_dir, mut store) = store();
+        let result = create(
+            &mut store,
+            Recurrence::Monthly { day: 31 },
+            "2027-02-01T09:00:00Z",
+            Some("2027-02-27T00:00:00Z"),
+        );
+        assert!(matches!(
+            result,
+            Err(ScheduleError::Rejected(
+                DomainError::ScheduleEndsBeforeFirstRun
+            ))
+        ));
+    }
+
+    #[test]
+    fn schedules_survive_reopening_the_store() {
+        let dir = TempDir::new().unwrap();
+        let mut store = ScheduleStore::open(dir.path(), 0).unwrap();
+        let schedule = create(&mut store, Recurrence::Daily, "2027-01-01T09:00:00Z", None).unwrap();
+        store
+            .advance(schedule.id, at("2027-01-01T09:00:00Z"))
+            .unwrap();
+        drop(store);
+
+        let store = ScheduleStore::open(dir.path(), 0).unwrap();
+        assert_eq!(
+            store.get(schedule.id).unwrap().next_run_at,
+            Some(at("2027-01-02T09:00:00Z"))
+        );
+    }
+}
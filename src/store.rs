@@ -0,0 +1,124 @@
+use crate::error::DomainError;
+use crate::ledger::JournalEntry;
+use crate::money::Money;
+use crate::secure_account::BankAccount;
+use std::fmt;
+use uuid::Uuid;
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// No account with this id exists.
+    AccountNotFound(Uuid),
+    /// The operation broke a business rule, e.g. insufficient funds.
+    Rejected(DomainError),
+    /// The account is no longer at the version the client expected.
+    VersionMismatch(Uuid),
+    /// The backend itself failed, e.g. the event log could not be written.
+    Backend(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::AccountNotFound(id) => write!(f, "Account {id} not found."),
+            StoreError::Rejected(e) => e.fmt(f),
+            StoreError::VersionMismatch(id) => write!(f, "Account {id} has changed."),
+            StoreError::Backend(reason) => write!(f, "Storage failure: {reason}"),
+        }
+    }
+}
+
+impl From<DomainError> for StoreError {
+    fn from(e: DomainError) -> Self {
+        StoreError::Rejected(e)
+    }
+}
+
+/// What a committed secure transfer did, as reported by its [`TransferPlan`].
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    /// Taken from the sender, in the sender's currency.
+    pub debited: Money,
+    /// Given to the receiver, in the receiver's currency.
+    pub credited: Money,
+    pub journal_entry: JournalEntry,
+}
+
+/// The body of a transfer: given both accounts, move the money through their
+/// checked methods and describe what was done. Returning an error aborts the
+/// transfer and discards any change made to the accounts.
+pub type TransferPlan<'a> =
+    dyn FnMut(&mut BankAccount, &mut BankAccount) -> Result<Transfer, StoreError> + 'a;
+
+/// The body of a single-account change: mutate the account through its methods
+/// and return the journal entry to post with it, if money moved. Returning an
+/// error aborts the change.
+pub type UpdatePlan<'a> =
+    dyn FnMut(&mut BankAccount) -> Result<Option<JournalEntry>, StoreError> + 'a;
+
+/// The body of a change spanning several accounts: given the accounts in the
+/// order their ids were passed, change them through their methods and return
+/// the journal entries to post. Returning an error aborts the whole change.
+pub type BatchPlan<'a> =
+    dyn FnMut(&mut [BankAccount]) -> Result<Vec<JournalEntry>, StoreError> + 'a;
+
+/// Storage for secure accounts and the journal that backs them.
+///
+/// Handlers are written once against this trait; each backend decides how to
+/// make its operations durable and atomic.
+pub trait AccountStore: Send + Sync {
+    fn get(&self, id: Uuid) -> Result<BankAccount, StoreError>;
+
+    /// Every account, in no particular order.
+    fn list(&self) -> Result<Vec<BankAccount>, StoreError>;
+
+    /// Stores a new account together with the journal entry for its opening balance.
+    fn create(&self, account: BankAccount, opening: JournalEntry) -> Result<(), StoreError>;
+
+    /// Stores a new account whose opening balance is paid by an existing one:
+    /// runs `plan` against the funding account and the new account, then commits
+    /// both with the journal entry atomically. If anything fails, the account is
+    /// not opened.
+    fn create_funded(
+        &self,
+        account: BankAccount,
+        from: Uuid,
+        plan: &mut TransferPlan<'_>,
+    ) -> Result<Transfer, StoreError>;
+
+    /// Runs `plan` against the two accounts and commits the result atomically:
+    /// either both accounts and the journal entry are stored, or nothing is.
+    /// A reversal is refused if the transfer it undoes was already reversed.
+    fn transfer(
+        &self,
+        from: Uuid,
+        to: Uuid,
+        plan: &mut TransferPlan<'_>,
+    ) -> Result<Transfer, StoreError>;
+
+    /// Runs `plan` against one account and commits the result atomically,
+    /// returning the account as stored.
+    fn update(&self, id: Uuid, plan: &mut UpdatePlan<'_>) -> Result<BankAccount, StoreError>;
+
+    /// Runs `plan` against several distinct accounts and commits the result
+    /// atomically: every account and journal entry is stored, or nothing is.
+    fn update_many(
+        &self,
+        ids: &[Uuid],
+        plan: &mut BatchPlan<'_>,
+    ) -> Result<Vec<BankAccount>, StoreError>;
+
+    /// Every journal entry, oldest first.
+    fn journal(&self) -> Result<Vec<JournalEntry>, StoreError>;
+
+    fn journal_entry(&self, id: Uuid) -> Result<Option<JournalEntry>, StoreError>;
+
+    /// The id of the journal entry reversing entry `id`, if there is one.
+    fn reversal_of(&self, id: Uuid) -> Result<Option<Uuid>, StoreError>;
+}
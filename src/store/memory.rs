@@ -0,0 +1,469 @@
+use super::{AccountStore, BatchPlan, StoreError, Transfer, TransferPlan, UpdatePlan};
+use crate::error::DomainError;
+use crate::events::{EventLog, EventSourced, Pending};
+use crate::ledger::{JournalEntry, Ledger};
+use crate::locks::AccountLocks;
+use crate::money::Money;
+use crate::secure_account::BankAccount;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// Changes to the secure store, as written to its event log.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    AccountOpened {
+        account: BankAccount,
+        journal_entry: JournalEntry,
+        /// The account that paid the opening balance, as it is afterwards.
+        #[serde(default)]
+        funded_by: Option<BankAccount>,
+    },
+    TransferCompleted {
+        from_account: Uuid,
+        to_account: Uuid,
+        debited: Money,
+        credited: Money,
+        journal_entry: JournalEntry,
+    },
+    /// A single account changed, e.g. its status. Records the account as it is
+    /// afterwards, plus the journal entry if money moved.
+    AccountUpdated {
+        account: BankAccount,
+        journal_entry: Option<JournalEntry>,
+    },
+    /// Several accounts changed together, e.g. in a batch transfer.
+    AccountsUpdated {
+        accounts: Vec<BankAccount>,
+        journal_entries: Vec<JournalEntry>,
+    },
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    accounts: HashMap<Uuid, BankAccount>,
+    ledger: Ledger,
+}
+
+impl EventSourced for State {
+    type Event = Event;
+
+    fn apply(&mut self, event: Event) -> Result<(), String> {
+        match event {
+            Event::AccountOpened {
+                account,
+                journal_entry,
+                funded_by,
+            } => {
+                if let Some(funding) = funded_by {
+                    if !self.accounts.contains_key(&funding.account_number) {
+                        return Err(format!(
+                            "unknown funding account {}",
+                            funding.account_number
+                        ));
+                    }
+                    self.accounts.insert(funding.account_number, funding);
+                }
+                self.accounts.insert(account.account_number, account);
+                self.ledger.append(journal_entry);
+            }
+            Event::TransferCompleted {
+                from_account,
+                to_account,
+                debited,
+                credited,
+                journal_entry,
+            } => {
+                // Go through the same checked methods the handler used, so a log that
+                // describes an impossible transfer is caught instead of silently applied.
+                let from = self
+                    .accounts
+                    .get_mut(&from_account)
+                    .ok_or_else(|| format!("unknown sender {from_account}"))?;
+                from.withdraw(debited, from.currency())
+                    .map_err(|e| e.to_string())?;
+                let to = self
+                    .accounts
+                    .get_mut(&to_account)
+                    .ok_or_else(|| format!("unknown receiver {to_account}"))?;
+                to.deposit(credited, to.currency())
+                    .map_err(|e| e.to_string())?;
+                self.ledger.append(journal_entry);
+            }
+            Event::AccountUpdated {
+                account,
+                journal_entry,
+            } => {
+                if !self.accounts.contains_key(&account.account_number) {
+                    return Err(format!("unknown account {}", account.account_number));
+                }
+                self.accounts.insert(account.account_number, account);
+                if let Some(entry) = journal_entry {
+                    self.ledger.append(entry);
+                }
+            }
+            Event::AccountsUpdated {
+                accounts,
+                journal_entries,
+            } => {
+                if let Some(account) = accounts
+                    .iter()
+                    .find(|account| !self.accounts.contains_key(&account.account_number))
+                {
+                    return Err(format!("unknown account {}", account.account_number));
+                }
+                for account in accounts {
+                    self.accounts.insert(account.account_number, account);
+                }
+                for entry in journal_entries {
+                    self.ledger.append(entry);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Inner {
+    state: State,
+    log: EventLog<State>,
+}
+
+impl Inner {
+    fn account(&self, id: Uuid) -> Result<BankAccount, StoreError> {
+        self.state
+            .accounts
+            .get(&id)
+            .cloned()
+            .ok_or(StoreError::AccountNotFound(id))
+    }
+
+    /// Writes `event` to the log and then applies it; nothing changes if the
+    /// write fails. The event is not durable until the returned [`Pending`] is.
+    fn write(&mut self, event: Event) -> Result<Pending, StoreError> {
+        let pending = self
+            .log
+            .write(&event)
+            .map_err(|e| StoreError::Backend(format!("failed to record event: {e}")))?;
+        self.state.apply(event).map_err(StoreError::Backend)?;
+        if let Err(e) = self.log.snapshot_if_due(&self.state) {
+            // The event is already in the log, so a missing snapshot only costs replay time.
+            eprintln!("⚠️  Failed to write snapshot: {e}");
+        }
+        Ok(pending)
+    }
+}
+
+/// Writes `event` under the store lock, then releases it before waiting for the
+/// event to reach the disk, so other operations can write while this one syncs.
+fn commit(mut inner: MutexGuard<'_, Inner>, event: Event) -> Result<(), StoreError> {
+    let pending = inner.write(event)?;
+    drop(inner);
+    pending
+        .wait()
+        .map_err(|e| StoreError::Backend(format!("failed to record event: {e}")))
+}
+
+/// Keeps secure accounts and the ledger in memory, made durable by an event log
+/// that is replayed on startup.
+///
+/// An operation holds the locks of the accounts it touches from the moment it
+/// reads them until its event is durable, so it is atomic per account. The
+/// store-wide mutex is only held to copy accounts out and to write and apply an
+/// event, never while a plan runs or the log syncs, and it is always taken after
+/// the account locks. Operations on disjoint accounts therefore run their plans
+/// side by side and share log syncs.
+///
+/// Reads may see an event that is applied but still syncing. If that sync fails,
+/// the operation reports a backend error and the store takes no more writes, so
+/// nothing is built on the change; a restart replays what reached the disk.
+pub struct MemoryStore {
+    inner: Mutex<Inner>,
+    locks: AccountLocks,
+}
+
+impl MemoryStore {
+    pub fn open(dir: impl AsRef<Path>, snapshot_every: u64) -> std::io::Result<Self> {
+        let (log, state) = EventLog::open(dir, snapshot_every)?;
+        Ok(Self {
+            inner: Mutex::new(Inner { state, log }),
+            locks: AccountLocks::default(),
+        })
+    }
+}
+
+impl AccountStore for MemoryStore {
+    fn get(&self, id: Uuid) -> Result<BankAccount, StoreError> {
+        self.inner.lock().unwrap().account(id)
+    }
+
+    fn list(&self) -> Result<Vec<BankAccount>, StoreError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.state.accounts.values().cloned().collect())
+    }
+
+    fn create(&self, account: BankAccount, opening: JournalEntry) -> Result<(), StoreError> {
+        let inner = self.inner.lock().unwrap();
+        if inner.state.accounts.contains_key(&account.account_number) {
+            return Err(StoreError::Rejected(DomainError::AccountExists));
+        }
+        commit(
+            inner,
+            Event::AccountOpened {
+                account,
+                journal_entry: opening,
+                funded_by: None,
+            },
+        )
+    }
+
+    fn create_funded(
+        &self,
+        mut account: BankAccount,
+        from: Uuid,
+        plan: &mut TransferPlan<'_>,
+    ) -> Result<Transfer, StoreError> {
+        let id = account.account_number;
+        self.locks.with_locked(&[from, id], || {
+            let mut funding = {
+                let inner = self.inner.lock().unwrap();
+                if inner.state.accounts.contains_key(&id) {
+                    return Err(StoreError::Rejected(DomainError::AccountExists));
+                }
+                inner.account(from)?
+            };
+            let transfer = plan(&mut funding, &mut account)?;
+
+            let inner = self.inner.lock().unwrap();
+            // `create` does not take account locks, so check again before committing.
+            if inner.state.accounts.contains_key(&id) {
+                return Err(StoreError::Rejected(DomainError::AccountExists));
+            }
+            commit(
+                inner,
+                Event::AccountOpened {
+                    account: account.clone(),
+                    journal_entry: transfer.journal_entry.clone(),
+                    funded_by: Some(funding),
+                },
+            )?;
+            Ok(transfer)
+        })
+    }
+
+    fn transfer(
+        &self,
+        from: Uuid,
+        to: Uuid,
+        plan: &mut TransferPlan<'_>,
+    ) -> Result<Transfer, StoreError> {
+        self.locks.with_locked(&[from, to], || {
+            // Work on copies: the plan may fail halfway, and the stored accounts must
+            // only change once the event is safely in the log.
+            let (mut from_account, mut to_account) = {
+                let inner = self.inner.lock().unwrap();
+                (inner.account(from)?, inner.account(to)?)
+            };
+            let transfer = plan(&mut from_account, &mut to_account)?;
+
+            let inner = self.inner.lock().unwrap();
+            // Both reversals of one transfer lock the same two accounts, so this
+            // check cannot race with the other one's commit.
+            if let Some(original) = transfer.journal_entry.reverses
+                && inner.state.ledger.reversal_of(original).is_some()
+            {
+                return Err(StoreError::Rejected(DomainError::TransferAlreadyReversed));
+            }
+            commit(
+                inner,
+                Event::TransferCompleted {
+                    from_account: from,
+                    to_account: to,
+                    debited: transfer.debited,
+                    credited: transfer.credited,
+                    journal_entry: transfer.journal_entry.clone(),
+                },
+            )?;
+            Ok(transfer)
+        })
+    }
+
+    fn update(&self, id: Uuid, plan: &mut UpdatePlan<'_>) -> Result<BankAccount, StoreError> {
+        self.locks.with_locked(&[id], || {
+            let mut account = self.inner.lock().unwrap().account(id)?;
+            let journal_entry = plan(&mut account)?;
+
+            commit(
+                self.inner.lock().unwrap(),
+                Event::AccountUpdated {
+                    account: account.clone(),
+                    journal_entry,
+                },
+            )?;
+            Ok(account)
+        })
+    }
+
+    fn update_many(
+        &self,
+        ids: &[Uuid],
+        plan: &mut BatchPlan<'_>,
+    ) -> Result<Vec<BankAccount>, StoreError> {
+        self.locks.with_locked(ids, || {
+            let mut accounts = {
+                let inner = self.inner.lock().unwrap();
+                ids.iter()
+                    .map(|&id| inner.account(id))
+                    .collect::<Result<Vec<_>, _>>()?
+            };
+            let journal_entries = plan(&mut accounts)?;
+
+            commit(
+                self.inner.lock().unwrap(),
+                Event::AccountsUpdated {
+                    accounts: accounts.clone(),
+                    journal_entries,
+                },
+            )?;
+            Ok(accounts)
+        })
+    }
+
+    fn journal(&self) -> Result<Vec<JournalEntry>, StoreError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.state.ledger.entries().to_vec())
+    }
+
+    fn journal_entry(&self, id: Uuid) -> Result<Option<JournalEntry>, StoreError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.state.ledger.entry(id).cloned())
+    }
+
+    fn reversal_of(&self, id: Uuid) -> Result<Option<Uuid>, StoreError> {
+        Ok(self.inner.lock().unwrap().state.ledger.reversal_of(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+    use crate::ledger::{EntryKind, transfer_postings};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn open_account(store: &MemoryStore, balance: i64) -> Uuid {
+        let account = BankAccount::new(Money::from_minor_units(balance), Currency::DEFAULT);
+        let id = account.account_number;
+        let opening = JournalEntry::new(EntryKind::AccountOpening, Vec::new()).unwrap();
+        store.create(account, opening).unwrap();
+        id
+    }
+
+    /// Moves `amount` the way the transfer handler does.
+    fn move_money(
+        from: &mut BankAccount,
+        to: &mut BankAccount,
+        amount: i64,
+    ) -> Result<Transfer, StoreError> {
+        let amount = Money::from_minor_units(amount);
+        from.withdraw(amount, from.currency())?;
+        to.deposit(amount, to.currency())?;
+        let postings = transfer_postings(
+            from.account_number,
+            amount,
+            from.currency(),
+            to.account_number,
+            amount,
+            to.currency(),
+        )?;
+        Ok(Transfer {
+            debited: amount,
+            credited: amount,
+            journal_entry: JournalEntry::new(EntryKind::Transfer, postings)?,
+        })
+    }
+
+    /// A transfer whose plan announces that it is running and then waits for
+    /// the other one to be running too.
+    fn meet(
+        store: &MemoryStore,
+        (from, to): (Uuid, Uuid),
+        arrived: Sender<()>,
+        other: Receiver<()>,
+    ) -> Result<Transfer, StoreError> {
+        store.transfer(from, to, &mut |from, to| {
+            arrived.send(()).unwrap();
+            other
+                .recv_timeout(Duration::from_secs(5))
+                .map_err(|_| StoreError::Backend("the other transfer never ran".to_owned()))?;
+            move_money(from, to, 10)
+        })
+    }
+
+    #[test]
+    fn transfers_on_disjoint_accounts_run_at_the_same_time() {
+        let dir = TempDir::new().unwrap();
+        let store = MemoryStore::open(dir.path(), 0).unwrap();
+        let first = (open_account(&store, 100), open_account(&store, 0));
+        let second = (open_account(&store, 100), open_account(&store, 0));
+        let (first_arrived, first_seen) = mpsc::channel();
+        let (second_arrived, second_seen) = mpsc::channel();
+
+        // Each plan only finishes once the other has started, so this fails
+        // instead of passing if the store runs them one after the other.
+        thread::scope(|scope| {
+            let store = &store;
+            let one = scope.spawn(move || meet(store, first, first_arrived, second_seen));
+            let two = scope.spawn(move || meet(store, second, second_arrived, first_seen));
+            one.join().unwrap().unwrap();
+            two.join().unwrap().unwrap();
+        });
+        assert_eq!(
+            store.get(first.1).unwrap().balance(),
+            Money::from_minor_units(10)
+        );
+        assert_eq!(
+            store.get(second.1).unwrap().balance(),
+            Money::from_minor_units(10)
+        );
+    }
+
+    #[test]
+    fn concurrent_transfers_replay_to_the_same_state() {
+        let dir = TempDir::new().unwrap();
+        let store = MemoryStore::open(dir.path(), 0).unwrap();
+        let accounts: Vec<Uuid> = (0..4).map(|_| open_account(&store, 1_000)).collect();
+
+        thread::scope(|scope| {
+            for thread in 0..8 {
+                let (store, accounts) = (&store, &accounts);
+                scope.spawn(move || {
+                    for round in 0..20 {
+                        let from = accounts[(thread + round) % accounts.len()];
+                        let to = accounts[(thread + round + 1) % accounts.len()];
+                        store
+                            .transfer(from, to, &mut |from, to| move_money(from, to, 1))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        let balances: Vec<Money> = accounts
+            .iter()
+            .map(|&id| store.get(id).unwrap().balance())
+            .collect();
+        drop(store);
+
+        let reopened = MemoryStore::open(dir.path(), 0).unwrap();
+        for (&id, &balance) in accounts.iter().zip(&balances) {
+            assert_eq!(reopened.get(id).unwrap().balance(), balance);
+        }
+        assert_eq!(reopened.journal().unwrap().len(), 4 + 8 * 20);
+    }
+}
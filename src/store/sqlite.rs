@@ -0,0 +1,667 @@
+use super::{AccountStore, BatchPlan, StoreError, Transfer, TransferPlan, UpdatePlan};
+use crate::currency::Currency;
+use crate::error::DomainError;
+use crate::ledger::{EntryKind, JournalEntry, LedgerAccount, Posting, Side};
+use crate::money::Money;
+use crate::secure_account::{AccountRecord, AccountStatus, BankAccount, Hold};
+use rusqlite::{Connection, OptionalExtension, Row, Transaction, TransactionBehavior, params};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Schema changes, applied in order. The database's `user_version` records how
+/// many have run, so each one runs exactly once. Never edit an entry that has
+/// shipped; append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: accounts and the double-entry journal.
+    "CREATE TABLE accounts (
+         account_number TEXT PRIMARY KEY,
+         currency       TEXT NOT NULL,
+         balance        INTEGER NOT NULL CHECK (balance >= 0)
+     );
+     CREATE TABLE journal_entries (
+         position    INTEGER PRIMARY KEY AUTOINCREMENT,
+         id          TEXT NOT NULL UNIQUE,
+         kind        TEXT NOT NULL,
+         recorded_at TEXT NOT NULL
+     );
+     CREATE TABLE postings (
+         entry_id     TEXT NOT NULL REFERENCES journal_entries (id),
+         line         INTEGER NOT NULL,
+         account_type TEXT NOT NULL,
+         account_id   TEXT,
+         side         TEXT NOT NULL CHECK (side IN ('debit', 'credit')),
+         amount       INTEGER NOT NULL CHECK (amount > 0),
+         currency     TEXT NOT NULL,
+         PRIMARY KEY (entry_id, line)
+     );",
+    // 2: account lifecycle status.
+    "ALTER TABLE accounts ADD COLUMN status TEXT NOT NULL DEFAULT 'active'
+         CHECK (status IN ('active', 'frozen', 'closed'));",
+    // 3: overdraft limits. SQLite cannot alter a CHECK constraint in place, so the
+    // table is rebuilt with the balance bounded by the limit instead of by zero.
+    "CREATE TABLE accounts_new (
+         account_number  TEXT PRIMARY KEY,
+         currency        TEXT NOT NULL,
+         balance         INTEGER NOT NULL,
+         status          TEXT NOT NULL DEFAULT 'active'
+             CHECK (status IN ('active', 'frozen', 'closed')),
+         overdraft_limit INTEGER NOT NULL DEFAULT 0 CHECK (overdraft_limit >= 0),
+         CHECK (balance >= -overdraft_limit)
+     );
+     INSERT INTO accounts_new (account_number, currency, balance, status)
+         SELECT account_number, currency, balance, status FROM accounts;
+     DROP TABLE accounts;
+     ALTER TABLE accounts_new RENAME TO accounts;",
+    // 4: holds reserving part of an account's balance.
+    "CREATE TABLE holds (
+         id             TEXT PRIMARY KEY,
+         account_number TEXT NOT NULL REFERENCES accounts (account_number),
+         amount         INTEGER NOT NULL CHECK (amount > 0),
+         placed_at      TEXT NOT NULL
+     );
+     CREATE INDEX holds_by_account ON holds (account_number);",
+    // 5: account versions for optimistic concurrency.
+    "ALTER TABLE accounts ADD COLUMN version INTEGER NOT NULL DEFAULT 0;",
+    // 6: links from a reversal to the transfer it undoes; a transfer is reversed at most once.
+    "ALTER TABLE journal_entries ADD COLUMN reverses TEXT REFERENCES journal_entries (id);
+     CREATE UNIQUE INDEX journal_entries_by_reverses ON journal_entries (reverses);",
+    // 7: account owners and opening times. Existing accounts get the Unix epoch.
+    "ALTER TABLE accounts ADD COLUMN owner TEXT;
+     ALTER TABLE accounts ADD COLUMN opened_at TEXT NOT NULL
+         DEFAULT '1970-01-01T00:00:00+00:00';",
+];
+
+/// Keeps secure accounts and the journal in an embedded SQLite database.
+///
+/// Every operation runs in a database transaction, and the schema refuses
+/// balances below the overdraft limit on its own, so a half-applied transfer can never be
+/// committed even if the checks in Rust were bypassed. Such a refusal is a
+/// [`StoreError::Backend`], as the checks in Rust should have caught it first.
+///
+/// There is a single connection, so operations run one at a time, reads included.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at `path` and brings its schema up to date.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let applied: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index as i64 + 1)?;
+        tx.commit()?;
+        println!("🗄️  Applied database migration {}", index + 1);
+    }
+    Ok(())
+}
+
+/// Every rule the schema enforces is checked in Rust before the write, so a
+/// constraint failing means something is broken, not that the request was wrong.
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Backend(e.to_string())
+    }
+}
+
+/// Reads a column that holds something parsed from text, e.g. a UUID.
+fn parse_column<T>(value: String, column: &str) -> Result<T, StoreError>
+where
+    T: std::str::FromStr,
+{
+    value
+        .parse()
+        .map_err(|_| StoreError::Backend(format!("invalid value in column {column}: {value}")))
+}
+
+fn status_to_sql(status: AccountStatus) -> &'static str {
+    match status {
+        AccountStatus::Active => "active",
+        AccountStatus::Frozen => "frozen",
+        AccountStatus::Closed => "closed",
+    }
+}
+
+fn status_from_sql(status: &str) -> Result<AccountStatus, StoreError> {
+    match status {
+        "active" => Ok(AccountStatus::Active),
+        "frozen" => Ok(AccountStatus::Frozen),
+        "closed" => Ok(AccountStatus::Closed),
+        other => Err(StoreError::Backend(format!(
+            "unknown account status {other}"
+        ))),
+    }
+}
+
+const ACCOUNT_COLUMNS: &str =
+    "account_number, currency, balance, status, overdraft_limit, version, owner, opened_at";
+
+/// Builds an account from a row selected with [`ACCOUNT_COLUMNS`], together
+/// with its holds.
+fn account_from_row(conn: &Connection, row: &Row<'_>) -> Result<BankAccount, StoreError> {
+    let account_number: String = row.get(0)?;
+    let currency: String = row.get(1)?;
+    let status: String = row.get(3)?;
+    let opened_at: String = row.get(7)?;
+    let account_number = parse_column(account_number, "account_number")?;
+    Ok(BankAccount::from(AccountRecord {
+        account_number,
+        balance: Money::from_minor_units(row.get(2)?),
+        currency: parse_column(currency, "currency")?,
+        status: status_from_sql(&status)?,
+        overdraft_limit: Money::from_minor_units(row.get(4)?),
+        holds: load_holds(conn, account_number)?,
+        version: row.get::<_, i64>(5)? as u64,
+        owner: row.get(6)?,
+        opened_at: parse_column(opened_at, "opened_at")?,
+    }))
+}
+
+fn load_holds(conn: &Connection, account_number: Uuid) -> Result<Vec<Hold>, StoreError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, amount, placed_at FROM holds WHERE account_number = ?1 ORDER BY placed_at",
+    )?;
+    let mut rows = stmt.query(params![account_number.to_string()])?;
+    let mut holds = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id: String = row.get(0)?;
+        let placed_at: String = row.get(2)?;
+        holds.push(Hold {
+            id: parse_column(id, "id")?,
+            amount: Money::from_minor_units(row.get(1)?),
+            placed_at: parse_column(placed_at, "placed_at")?,
+        });
+    }
+    Ok(holds)
+}
+
+fn load_account(tx: &Transaction<'_>, id: Uuid) -> Result<BankAccount, StoreError> {
+    let mut stmt = tx.prepare(&format!(
+        "SELECT {ACCOUNT_COLUMNS} FROM accounts WHERE account_number = ?1"
+    ))?;
+    let mut rows = stmt.query(params![id.to_string()])?;
+    match rows.next()? {
+        Some(row) => account_from_row(tx, row),
+        None => Err(StoreError::AccountNotFound(id)),
+    }
+}
+
+/// Inserts a newly opened account. New accounts have no holds yet.
+fn insert_account(tx: &Transaction<'_>, account: &BankAccount) -> Result<(), StoreError> {
+    let exists = tx
+        .query_row(
+            "SELECT 1 FROM accounts WHERE account_number = ?1",
+            [account.account_number.to_string()],
+            |_| Ok(()),
+        )
+        .optional()?;
+    if exists.is_some() {
+        return Err(StoreError::Rejected(DomainError::AccountExists));
+    }
+    tx.execute(
+        "INSERT INTO accounts
+             (account_number, currency, balance, status, overdraft_limit, version,
+              owner, opened_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            account.account_number.to_string(),
+            account.currency().as_str(),
+            account.balance().minor_units(),
+            status_to_sql(account.status()),
+            account.overdraft_limit().minor_units(),
+            account.version() as i64,
+            account.owner(),
+            account.opened_at().to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Writes back everything about an account that can change after it is opened.
+fn save_account(tx: &Transaction<'_>, account: &BankAccount) -> Result<(), StoreError> {
+    tx.execute(
+        "UPDATE accounts SET balance = ?1, status = ?2, overdraft_limit = ?3, version = ?4
+         WHERE account_number = ?5",
+        params![
+            account.balance().minor_units(),
+            status_to_sql(account.status()),
+            account.overdraft_limit().minor_units(),
+            account.version() as i64,
+            account.account_number.to_string()
+        ],
+    )?;
+    // Holds are few per account, so rewriting them all is simpler than diffing.
+    tx.execute(
+        "DELETE FROM holds WHERE account_number = ?1",
+        params![account.account_number.to_string()],
+    )?;
+    for hold in account.holds() {
+        tx.execute(
+            "INSERT INTO holds (id, account_number, amount, placed_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                hold.id.to_string(),
+                account.account_number.to_string(),
+                hold.amount.minor_units(),
+                hold.placed_at.to_rfc3339()
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn entry_kind_to_sql(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::AccountOpening => "account_opening",
+        EntryKind::Issuance => "issuance",
+        EntryKind::Deposit => "deposit",
+        EntryKind::Withdrawal => "withdrawal",
+        EntryKind::Transfer => "transfer",
+        EntryKind::HoldCapture => "hold_capture",
+        EntryKind::Reversal => "reversal",
+    }
+}
+
+fn entry_kind_from_sql(kind: &str) -> Result<EntryKind, StoreError> {
+    match kind {
+        "account_opening" => Ok(EntryKind::AccountOpening),
+        "issuance" => Ok(EntryKind::Issuance),
+        "deposit" => Ok(EntryKind::Deposit),
+        "withdrawal" => Ok(EntryKind::Withdrawal),
+        "transfer" => Ok(EntryKind::Transfer),
+        "hold_capture" => Ok(EntryKind::HoldCapture),
+        "reversal" => Ok(EntryKind::Reversal),
+        other => Err(StoreError::Backend(format!("unknown entry kind {other}"))),
+    }
+}
+
+fn ledger_account_to_sql(account: LedgerAccount) -> (&'static str, Option<String>) {
+    match account {
+        LedgerAccount::Customer(id) => ("customer", Some(id.to_string())),
+        LedgerAccount::Funding => ("funding", None),
+        LedgerAccount::FxClearing => ("fx_clearing", None),
+        LedgerAccount::Settlement => ("settlement", None),
+    }
+}
+
+fn ledger_account_from_sql(
+    account_type: &str,
+    account_id: Option<String>,
+) -> Result<LedgerAccount, StoreError> {
+    match (account_type, account_id) {
+        ("customer", Some(id)) => Ok(LedgerAccount::Customer(parse_column(id, "account_id")?)),
+        ("funding", None) => Ok(LedgerAccount::Funding),
+        ("fx_clearing", None) => Ok(LedgerAccount::FxClearing),
+        ("settlement", None) => Ok(LedgerAccount::Settlement),
+        (other, _) => Err(StoreError::Backend(format!(
+            "unknown ledger account {other}"
+        ))),
+    }
+}
+
+fn reversal_of(tx: &Transaction<'_>, id: Uuid) -> Result<Option<Uuid>, StoreError> {
+    let reversal: Option<String> = tx
+        .query_row(
+            "SELECT id FROM journal_entries WHERE reverses = ?1",
+            [id.to_string()],
+            |row| row.get(0),
+        )
+        .optional()?;
+    reversal.map(|id| parse_column(id, "id")).transpose()
+}
+
+fn insert_entry(tx: &Transaction<'_>, entry: &JournalEntry) -> Result<(), StoreError> {
+    // The unique index would refuse a second reversal too, but with a message
+    // about something else entirely.
+    if let Some(original) = entry.reverses
+        && reversal_of(tx, original)?.is_some()
+    {
+        return Err(StoreError::Rejected(DomainError::TransferAlreadyReversed));
+    }
+    tx.execute(
+        "INSERT INTO journal_entries (id, kind, recorded_at, reverses) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            entry.id.to_string(),
+            entry_kind_to_sql(entry.kind),
+            entry.recorded_at.to_rfc3339(),
+            entry.reverses.map(|id| id.to_string())
+        ],
+    )?;
+    for (line, posting) in (0_i64..).zip(&entry.postings) {
+        let (account_type, account_id) = ledger_account_to_sql(posting.account);
+        let side = match posting.side {
+            Side::Debit => "debit",
+            Side::Credit => "credit",
+        };
+        tx.execute(
+            "INSERT INTO postings (entry_id, line, account_type, account_id, side, amount, currency)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                entry.id.to_string(),
+                line,
+                account_type,
+                account_id,
+                side,
+                posting.amount.minor_units(),
+                posting.currency.as_str()
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Journal entries in the order they were recorded: every entry, or only entry `id`.
+fn load_entries(tx: &Transaction<'_>, id: Option<Uuid>) -> Result<Vec<JournalEntry>, StoreError> {
+    let id = id.map(|id| id.to_string());
+
+    let mut postings: HashMap<String, Vec<Posting>> = HashMap::new();
+    {
+        let mut stmt = tx.prepare(
+            "SELECT entry_id, account_type, account_id, side, amount, currency
+             FROM postings WHERE ?1 IS NULL OR entry_id = ?1 ORDER BY entry_id, line",
+        )?;
+        let mut rows = stmt.query([&id])?;
+        while let Some(row) = rows.next()? {
+            let account_type: String = row.get(1)?;
+            let side: String = row.get(3)?;
+            let currency: String = row.get(5)?;
+            let posting = Posting {
+                account: ledger_account_from_sql(&account_type, row.get(2)?)?,
+                side: match side.as_str() {
+                    "debit" => Side::Debit,
+                    _ => Side::Credit,
+                },
+                amount: Money::from_minor_units(row.get(4)?),
+                currency: parse_column::<Currency>(currency, "currency")?,
+            };
+            postings.entry(row.get(0)?).or_default().push(posting);
+        }
+    }
+
+    let mut stmt = tx.prepare(
+        "SELECT id, kind, recorded_at, reverses FROM journal_entries
+         WHERE ?1 IS NULL OR id = ?1 ORDER BY position",
+    )?;
+    let mut rows = stmt.query([&id])?;
+    let mut entries = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id: String = row.get(0)?;
+        let kind: String = row.get(1)?;
+        let recorded_at: String = row.get(2)?;
+        let reverses: Option<String> = row.get(3)?;
+        entries.push(JournalEntry {
+            postings: postings.remove(&id).unwrap_or_default(),
+            id: parse_column(id, "id")?,
+            kind: entry_kind_from_sql(&kind)?,
+            recorded_at: parse_column(recorded_at, "recorded_at")?,
+            reverses: reverses
+                .map(|id| parse_column(id, "reverses"))
+                .transpose()?,
+        });
+    }
+    Ok(entries)
+}
+
+impl AccountStore for SqliteStore {
+    fn get(&self, id: Uuid) -> Result<BankAccount, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        load_account(&tx, id)
+    }
+
+    fn list(&self) -> Result<Vec<BankAccount>, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        // One read transaction, so every account and its holds agree.
+        let tx = conn.transaction()?;
+        let mut stmt = tx.prepare(&format!("SELECT {ACCOUNT_COLUMNS} FROM accounts"))?;
+        let mut rows = stmt.query([])?;
+        let mut accounts = Vec::new();
+        while let Some(row) = rows.next()? {
+            accounts.push(account_from_row(&tx, row)?);
+        }
+        Ok(accounts)
+    }
+
+    fn create(&self, account: BankAccount, opening: JournalEntry) -> Result<(), StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        insert_account(&tx, &account)?;
+        insert_entry(&tx, &opening)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn create_funded(
+        &self,
+        mut account: BankAccount,
+        from: Uuid,
+        plan: &mut TransferPlan<'_>,
+    ) -> Result<Transfer, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let mut funding = load_account(&tx, from)?;
+        let transfer = plan(&mut funding, &mut account)?;
+
+        insert_account(&tx, &account)?;
+        save_account(&tx, &funding)?;
+        insert_entry(&tx, &transfer.journal_entry)?;
+        tx.commit()?;
+        Ok(transfer)
+    }
+
+    fn transfer(
+        &self,
+        from: Uuid,
+        to: Uuid,
+        plan: &mut TransferPlan<'_>,
+    ) -> Result<Transfer, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        // IMMEDIATE takes the write lock up front, so no other writer can change
+        // either balance between reading it and writing it back.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let mut from_account = load_account(&tx, from)?;
+        let mut to_account = load_account(&tx, to)?;
+        // Any early return from here on drops `tx`, which rolls everything back.
+        let transfer = plan(&mut from_account, &mut to_account)?;
+
+        save_account(&tx, &from_account)?;
+        save_account(&tx, &to_account)?;
+        insert_entry(&tx, &transfer.journal_entry)?;
+        tx.commit()?;
+        Ok(transfer)
+    }
+
+    fn update(&self, id: Uuid, plan: &mut UpdatePlan<'_>) -> Result<BankAccount, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let mut account = load_account(&tx, id)?;
+        let journal_entry = plan(&mut account)?;
+
+        save_account(&tx, &account)?;
+        if let Some(entry) = &journal_entry {
+            insert_entry(&tx, entry)?;
+        }
+        tx.commit()?;
+        Ok(account)
+    }
+
+    fn update_many(
+        &self,
+        ids: &[Uuid],
+        plan: &mut BatchPlan<'_>,
+    ) -> Result<Vec<BankAccount>, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let mut accounts = ids
+            .iter()
+            .map(|&id| load_account(&tx, id))
+            .collect::<Result<Vec<_>, _>>()?;
+        let journal_entries = plan(&mut accounts)?;
+
+        for account in &accounts {
+            save_account(&tx, account)?;
+        }
+        for entry in &journal_entries {
+            insert_entry(&tx, entry)?;
+        }
+        tx.commit()?;
+        Ok(accounts)
+    }
+
+    fn journal(&self) -> Result<Vec<JournalEntry>, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        // One read transaction, so the entries and their postings agree.
+        let tx = conn.transaction()?;
+        load_entries(&tx, None)
+    }
+
+    fn journal_entry(&self, id: Uuid) -> Result<Option<JournalEntry>, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        Ok(load_entries(&tx, Some(id))?.pop())
+    }
+
+    fn reversal_of(&self, id: Uuid) -> Result<Option<Uuid>, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        reversal_of(&tx, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn opening() -> JournalEntry {
+        JournalEntry::new(EntryKind::AccountOpening, Vec::new()).unwrap()
+    }
+
+    fn account(balance: i64) -> BankAccount {
+        BankAccount::new(Money::from_minor_units(balance), Currency::DEFAULT)
+    }
+
+    fn user_version(conn: &Connection) -> i64 {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn a_new_database_gets_every_migration_once() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("secure.db");
+        let account = account(100);
+        let id = account.account_number;
+        SqliteStore::open(&path)
+            .unwrap()
+            .create(account, opening())
+            .unwrap();
+
+        // Opening it again runs nothing twice and keeps what was stored.
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(
+            user_version(&store.conn.lock().unwrap()),
+            MIGRATIONS.len() as i64
+        );
+        assert_eq!(
+            store.get(id).unwrap().balance(),
+            Money::from_minor_units(100)
+        );
+        assert_eq!(store.journal().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn an_old_database_is_brought_up_to_date_with_its_rows() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("secure.db");
+        let id = Uuid::new_v4();
+        {
+            // A database as the first two migrations left it.
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(MIGRATIONS[0]).unwrap();
+            conn.execute_batch(MIGRATIONS[1]).unwrap();
+            conn.pragma_update(None, "user_version", 2).unwrap();
+            conn.execute(
+                "INSERT INTO accounts (account_number, currency, balance, status)
+                 VALUES (?1, 'USD', 250, 'frozen')",
+                [id.to_string()],
+            )
+            .unwrap();
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        let account = store.get(id).unwrap();
+        assert_eq!(account.balance(), Money::from_minor_units(250));
+        assert_eq!(account.status(), AccountStatus::Frozen);
+        assert_eq!(account.overdraft_limit(), Money::ZERO);
+        assert_eq!(account.version(), 0);
+        assert_eq!(account.owner(), None);
+        assert_eq!(account.opened_at().timestamp(), 0);
+        assert!(account.holds().is_empty());
+    }
+
+    #[test]
+    fn opening_an_account_twice_is_refused_as_such() {
+        let dir = TempDir::new().unwrap();
+        let store = SqliteStore::open(dir.path().join("secure.db")).unwrap();
+        let account = account(10);
+        store.create(account.clone(), opening()).unwrap();
+
+        assert!(matches!(
+            store.create(account, opening()),
+            Err(StoreError::Rejected(DomainError::AccountExists))
+        ));
+        assert_eq!(store.journal().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn other_constraint_failures_are_backend_errors() {
+        let dir = TempDir::new().unwrap();
+        let store = SqliteStore::open(dir.path().join("secure.db")).unwrap();
+        let entry = opening();
+        store.create(account(10), entry.clone()).unwrap();
+
+        // A journal entry id that is already taken is not an existing account.
+        let second = account(10);
+        let id = second.account_number;
+        assert!(matches!(
+            store.create(second, entry),
+            Err(StoreError::Backend(_))
+        ));
+        assert!(matches!(store.get(id), Err(StoreError::AccountNotFound(_))));
+
+        // Nor is a balance below the overdraft limit that got past the checks in
+        // Rust; the schema still refuses it.
+        let overdrawn = BankAccount::from(AccountRecord {
+            account_number: Uuid::new_v4(),
+            balance: Money::from_minor_units(-1),
+            currency: Currency::DEFAULT,
+            status: AccountStatus::Active,
+            overdraft_limit: Money::ZERO,
+            holds: Vec::new(),
+            version: 0,
+            owner: None,
+            opened_at: chrono::Utc::now(),
+        });
+        assert!(matches!(
+            store.create(overdrawn, opening()),
+            Err(StoreError::Backend(_))
+        ));
+    }
+}
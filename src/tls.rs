@@ -0,0 +1,105 @@
+use rustls::ServerConfig;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+/// The server certificate, read from a PEM certificate chain and a PEM private
+/// key. [`Certificate::reload_if_changed`] swaps in new files without a restart;
+/// connections already open keep the certificate they started with.
+pub struct Certificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// When the two files were last modified, as of the last successful load.
+    loaded: Mutex<(SystemTime, SystemTime)>,
+}
+
+impl fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificate")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Certificate {
+    pub fn load(cert_path: PathBuf, key_path: PathBuf) -> io::Result<Self> {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let modified = modified(&cert_path, &key_path)?;
+        let key = read(&cert_path, &key_path, &provider)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            provider,
+            current: RwLock::new(Arc::new(key)),
+            loaded: Mutex::new(modified),
+        })
+    }
+
+    /// Loads the files again if either was modified since the last load.
+    /// Returns whether a new certificate is now in use. If the new files cannot be
+    /// used, e.g. because only one of them has been replaced yet, the current
+    /// certificate stays and the next call tries again.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let modified = modified(&self.cert_path, &self.key_path)?;
+        if modified == *loaded {
+            return Ok(false);
+        }
+        let key = read(&self.cert_path, &self.key_path, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        *loaded = modified;
+        Ok(true)
+    }
+
+    /// A TLS configuration that always serves the current certificate.
+    pub fn server_config(self: Arc<Self>) -> io::Result<ServerConfig> {
+        let config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(self);
+        Ok(config)
+    }
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> io::Result<(SystemTime, SystemTime)> {
+    Ok((
+        std::fs::metadata(cert_path)?.modified()?,
+        std::fs::metadata(key_path)?.modified()?,
+    ))
+}
+
+/// Reads the certificate chain and its private key, and checks that they belong
+/// together.
+fn read(cert_path: &Path, key_path: &Path, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+    let invalid = |path: &Path, reason: &dyn fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {reason}", path.display()),
+        )
+    };
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert_path, &e))?;
+    if chain.is_empty() {
+        return Err(invalid(cert_path, &"no certificate found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid(key_path, &e))?;
+    CertifiedKey::from_der(chain, key, provider).map_err(|e| invalid(key_path, &e))
+}
@@ -0,0 +1,126 @@
+use crate::currency::Currency;
+use crate::error::DomainError;
+use crate::ledger::{EntryKind, JournalEntry, Ledger, LedgerAccount, Side};
+use crate::money::Money;
+use crate::secure_account::BankAccount;
+use crate::store::{AccountStore, StoreError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Namespace for deriving treasury account numbers from currency codes.
+const NAMESPACE: Uuid = Uuid::from_u128(0x6f0e_4c1a_9b7d_4e52_8a31_d2c9_5f04_7be3);
+
+/// The account number of the treasury for `currency`. There is one treasury per
+/// currency, and its number is the same on every server.
+pub fn account_id(currency: Currency) -> Uuid {
+    Uuid::new_v5(&NAMESPACE, currency.as_str().as_bytes())
+}
+
+/// Opens the treasury for `currency`, empty, unless it already exists.
+pub fn open(store: &dyn AccountStore, currency: Currency) -> Result<Uuid, StoreError> {
+    let id = account_id(currency);
+    match store.get(id) {
+        Ok(_) => return Ok(id),
+        Err(StoreError::AccountNotFound(_)) => {}
+        Err(e) => return Err(e),
+    }
+    let mut account = BankAccount::new(Money::ZERO, currency);
+    account.account_number = id;
+    let opening = JournalEntry::new(EntryKind::AccountOpening, Vec::new())?;
+    match store.create(account, opening) {
+        // Someone else opened it in the meantime.
+        Ok(()) | Err(StoreError::Rejected(DomainError::AccountExists)) => Ok(id),
+        Err(e) => Err(e),
+    }
+}
+
+/// Where the money in one currency is.
+#[derive(Serialize)]
+pub struct SupplyLine {
+    pub currency: Currency,
+    pub treasury_account: Uuid,
+    /// Everything ever minted into the treasury.
+    pub minted: Money,
+    /// Everything customers paid in from outside the bank.
+    pub deposited: Money,
+    /// Everything that left the bank, by withdrawals and captured holds.
+    pub paid_out: Money,
+    /// What conversions from other currencies brought in, less what they took
+    /// out; negative if more was converted out of this currency than into it.
+    pub converted: Money,
+    /// What the treasury still holds, i.e. has not yet paid out to new accounts.
+    pub treasury_balance: Money,
+    /// The sum of every other account's balance.
+    pub in_circulation: Money,
+    /// Treasury balance plus money in circulation, which adds up to what was
+    /// minted, deposited and converted less what was paid out.
+    pub money_supply: Money,
+}
+
+/// Sums up the money supply per currency, from every account and the journal.
+pub fn supply(accounts: &[BankAccount], ledger: &Ledger) -> Result<Vec<SupplyLine>, DomainError> {
+    let mut lines: BTreeMap<String, SupplyLine> = BTreeMap::new();
+    for account in accounts {
+        let currency = account.currency();
+        let line = lines
+            .entry(currency.to_string())
+            .or_insert_with(|| SupplyLine {
+                currency,
+                treasury_account: account_id(currency),
+                minted: Money::ZERO,
+                deposited: Money::ZERO,
+                paid_out: Money::ZERO,
+                converted: Money::ZERO,
+                treasury_balance: Money::ZERO,
+                in_circulation: Money::ZERO,
+                money_supply: Money::ZERO,
+            });
+        if account.account_number == line.treasury_account {
+            line.treasury_balance = account.balance();
+        } else {
+            line.in_circulation = line.in_circulation.checked_add(account.balance())?;
+        }
+        line.money_supply = line.money_supply.checked_add(account.balance())?;
+    }
+
+    let minted = ledger
+        .entries()
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::Issuance)
+        .flat_map(|entry| &entry.postings)
+        .filter(|posting| posting.side == Side::Credit);
+    for posting in minted {
+        if let Some(line) = lines.get_mut(posting.currency.as_str())
+            && posting.account == LedgerAccount::Customer(line.treasury_account)
+        {
+            line.minted = line.minted.checked_add(posting.amount)?;
+        }
+    }
+
+    // Apart from minting, money only enters or leaves a currency through the
+    // accounts standing for the outside world and through conversions.
+    for entry in ledger.entries() {
+        for posting in &entry.postings {
+            let Some(line) = lines.get_mut(posting.currency.as_str()) else {
+                continue;
+            };
+            match (posting.account, posting.side) {
+                (LedgerAccount::Funding, Side::Debit) if entry.kind != EntryKind::Issuance => {
+                    line.deposited = line.deposited.checked_add(posting.amount)?;
+                }
+                (LedgerAccount::Funding | LedgerAccount::Settlement, Side::Credit) => {
+                    line.paid_out = line.paid_out.checked_add(posting.amount)?;
+                }
+                (LedgerAccount::FxClearing, Side::Debit) => {
+                    line.converted = line.converted.checked_add(posting.amount)?;
+                }
+                (LedgerAccount::FxClearing, Side::Credit) => {
+                    line.converted = line.converted.checked_sub(posting.amount)?;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(lines.into_values().collect())
+}
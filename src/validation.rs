@@ -0,0 +1,344 @@
+use crate::error::ApiError;
+use crate::money::Money;
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest, web};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+
+/// The most a single request may move, in minor units: ten million in a
+/// currency with cents. Larger amounts are far more likely to be typos than
+/// payments.
+pub const MAX_AMOUNT: Money = Money::from_minor_units(1_000_000_000);
+
+/// A JSON body that was read into `T` and passed `T`'s validation rules before
+/// the handler runs. A body that breaks any rule is refused with 400
+/// `validation_failed`, listing every broken rule at once.
+pub struct ValidJson<T>(pub T);
+
+impl<T> Deref for ValidJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for ValidJson<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let body = json.await?.into_inner();
+            body.validate()
+                .map_err(|e| ApiError::ValidationFailed(field_errors(&e)))?;
+            Ok(ValidJson(body))
+        })
+    }
+}
+
+/// A query string that was read into `T` and passed `T`'s validation rules,
+/// refused like a [`ValidJson`] body if it breaks any.
+pub struct ValidQuery<T>(pub T);
+
+impl<T> Deref for ValidQuery<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for ValidQuery<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let query = web::Query::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let query = query.await?.into_inner();
+            query
+                .validate()
+                .map_err(|e| ApiError::ValidationFailed(field_errors(&e)))?;
+            Ok(ValidQuery(query))
+        })
+    }
+}
+
+/// An error for a rule that spans several fields, i.e. a `schema` function,
+/// reported against the one field that has to change.
+pub fn struct_error(
+    field: &'static str,
+    code: &'static str,
+    message: &'static str,
+) -> ValidationError {
+    let mut error = ValidationError::new(code).with_message(Cow::Borrowed(message));
+    error.add_param(Cow::Borrowed("field"), &field);
+    error
+}
+
+/// One broken rule, as reported to the client.
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    /// Where in the body or query, e.g. `amount`, `legs[2].amount` or `limit`.
+    pub field: String,
+    /// Which rule, e.g. `range` or `length`.
+    pub code: String,
+    pub message: String,
+}
+
+/// Flattens the nested errors of a request into one list, ordered by field and
+/// list elements by index.
+fn field_errors(errors: &ValidationErrors) -> Vec<FieldError> {
+    let mut flat = Vec::new();
+    collect(errors, "", &mut flat);
+    flat
+}
+
+fn collect(errors: &ValidationErrors, prefix: &str, flat: &mut Vec<FieldError>) {
+    let mut fields: Vec<_> = errors.errors().iter().collect();
+    fields.sort_by_key(|(field, _)| *field);
+    for (field, kind) in fields {
+        let path = if prefix.is_empty() {
+            field.to_string()
+        } else {
+            format!("{prefix}.{field}")
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                flat.extend(errors.iter().map(|error| FieldError {
+                    field: match (field.as_ref(), error.params.get("field")) {
+                        // Struct-level errors from [`struct_error`] name their field.
+                        ("__all__", Some(Value::String(field))) if prefix.is_empty() => {
+                            field.clone()
+                        }
+                        ("__all__", Some(Value::String(field))) => format!("{prefix}.{field}"),
+                        _ => path.clone(),
+                    },
+                    code: error.code.to_string(),
+                    message: describe(error),
+                }));
+            }
+            ValidationErrorsKind::Struct(errors) => collect(errors, &path, flat),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect(errors, &format!("{path}[{index}]"), flat);
+                }
+            }
+        }
+    }
+}
+
+/// A message for a broken rule, unless the rule brings its own.
+fn describe(error: &ValidationError) -> String {
+    if let Some(message) = &error.message {
+        return message.to_string();
+    }
+    let param = |name: &str| error.params.get(name).map(Value::to_string);
+    match error.code.as_ref() {
+        "range" => match (param("min"), param("exclusive_min"), param("max")) {
+            (Some(min), _, Some(max)) => format!("must be between {min} and {max}"),
+            (_, Some(min), Some(max)) => format!("must be greater than {min} and at most {max}"),
+            (Some(min), _, None) => format!("must be at least {min}"),
+            (_, Some(min), None) => format!("must be greater than {min}"),
+            (None, None, Some(max)) => format!("must be at most {max}"),
+            (None, None, None) => "is out of range".to_owned(),
+        },
+        "length" => match (param("min"), param("max"), param("equal")) {
+            (_, _, Some(equal)) => format!("must have a length of {equal}"),
+            (Some(min), Some(max), None) => format!("must have a length between {min} and {max}"),
+            (Some(min), None, None) => format!("must have a length of at least {min}"),
+            (None, Some(max), None) => format!("must have a length of at most {max}"),
+            (None, None, None) => "has the wrong length".to_owned(),
+        },
+        code => format!("breaks the `{code}` rule"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Deserialize, Validate)]
+    #[validate(schema(function = check_window, skip_on_field_errors = false))]
+    struct Order {
+        #[validate(range(exclusive_min = Money::ZERO, max = MAX_AMOUNT))]
+        amount: Money,
+        #[validate(length(min = 1, max = 3), nested)]
+        legs: Vec<Leg>,
+        #[validate(length(max = 8))]
+        note: Option<String>,
+        from: u32,
+        to: u32,
+    }
+
+    #[derive(Deserialize, Serialize, Validate)]
+    struct Leg {
+        #[validate(range(min = 1))]
+        amount: u32,
+    }
+
+    fn check_window(order: &Order) -> Result<(), ValidationError> {
+        if order.to < order.from {
+            return Err(struct_error(
+                "to",
+                "ends_before_start",
+                "must not be before from",
+            ));
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, Deserialize, Validate)]
+    struct Page {
+        #[validate(range(min = 1, max = 200))]
+        limit: usize,
+    }
+
+    fn flat(errors: Vec<FieldError>) -> Vec<(String, String, String)> {
+        errors
+            .into_iter()
+            .map(|e| (e.field, e.code, e.message))
+            .collect()
+    }
+
+    fn broken(field: &str, code: &str, message: &str) -> (String, String, String) {
+        (field.to_owned(), code.to_owned(), message.to_owned())
+    }
+
+    fn refusal(error: actix_web::Error) -> Vec<(String, String, String)> {
+        match error.as_error::<ApiError>() {
+            Some(ApiError::ValidationFailed(errors)) => errors
+                .iter()
+                .map(|e| (e.field.clone(), e.code.clone(), e.message.clone()))
+                .collect(),
+            other => panic!("expected validation_failed, got {other:?}"),
+        }
+    }
+
+    async fn json(body: Value) -> Result<ValidJson<Order>, actix_web::Error> {
+        let (req, mut payload) = TestRequest::post().set_json(body).to_http_parts();
+        ValidJson::<Order>::from_request(&req, &mut payload).await
+    }
+
+    #[test]
+    fn every_broken_rule_is_listed_with_its_path() {
+        let order = Order {
+            amount: Money::ZERO,
+            legs: vec![Leg { amount: 0 }, Leg { amount: 5 }, Leg { amount: 0 }],
+            note: Some("far too long".to_owned()),
+            from: 5,
+            to: 2,
+        };
+        let errors = flat(field_errors(&order.validate().unwrap_err()));
+        assert_eq!(
+            errors,
+            [
+                broken("to", "ends_before_start", "must not be before from"),
+                broken(
+                    "amount",
+                    "range",
+                    "must be greater than 0 and at most 1000000000"
+                ),
+                broken("legs[0].amount", "range", "must be at least 1"),
+                broken("legs[2].amount", "range", "must be at least 1"),
+                broken("note", "length", "must have a length of at most 8"),
+            ]
+        );
+    }
+
+    #[test]
+    fn struct_errors_of_nested_structs_keep_their_prefix() {
+        #[derive(Validate)]
+        struct Outer {
+            #[validate(nested)]
+            order: Order,
+        }
+        let outer = Outer {
+            order: Order {
+                amount: Money::from_minor_units(1),
+                legs: vec![Leg { amount: 1 }],
+                note: None,
+                from: 2,
+                to: 1,
+            },
+        };
+        let errors = flat(field_errors(&outer.validate().unwrap_err()));
+        assert_eq!(
+            errors,
+            [broken(
+                "order.to",
+                "ends_before_start",
+                "must not be before from"
+            )]
+        );
+    }
+
+    #[test]
+    fn rules_without_a_message_of_their_own_name_their_code() {
+        let error = ValidationError::new("email");
+        assert_eq!(describe(&error), "breaks the `email` rule");
+        let error = ValidationError::new("range");
+        assert_eq!(describe(&error), "is out of range");
+    }
+
+    #[actix_web::test]
+    async fn a_valid_body_reaches_the_handler() {
+        let order = json(json!({
+            "amount": 250,
+            "legs": [{ "amount": 1 }],
+            "note": null,
+            "from": 1,
+            "to": 1,
+        }))
+        .await
+        .unwrap();
+        assert_eq!(order.amount, Money::from_minor_units(250));
+        assert_eq!(order.legs.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn an_invalid_body_is_refused_with_its_field_errors() {
+        let error = json(json!({
+            "amount": 250,
+            "legs": [],
+            "note": null,
+            "from": 3,
+            "to": 1,
+        }))
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(
+            refusal(error),
+            [
+                broken("to", "ends_before_start", "must not be before from"),
+                broken("legs", "length", "must have a length between 1 and 3"),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn an_invalid_query_is_refused_with_its_field_errors() {
+        let query = |uri| async move {
+            let (req, mut payload) = TestRequest::get().uri(uri).to_http_parts();
+            ValidQuery::<Page>::from_request(&req, &mut payload).await
+        };
+        assert_eq!(query("/?limit=20").await.unwrap().limit, 20);
+        let error = query("/?limit=500").await.err().unwrap();
+        assert_eq!(
+            refusal(error),
+            [broken("limit", "range", "must be between 1 and 200")]
+        );
+    }
+}